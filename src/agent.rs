@@ -16,16 +16,28 @@
 
 use crate::clipboard::ClipboardEvent;
 use crate::dbus_notifications::{DbusNotification, DbusNotificationClosed};
+use crate::drive::QemuDriveMount;
 use crate::runner::QemuSharedDir;
 use crate::util::open_socket;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
 use std::io::BufRead;
 use std::io::BufReader;
+use std::mem;
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Condvar, Mutex, TryLockError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Version of the agent protocol spoken by this host.
+pub const AGENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Returned by AgentHost::read_message, and the functions waiting for a
+// message, when the read timeout expires.
+pub const AGENT_TIMEOUT: &str = "agent timed out";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AgentReady {
@@ -42,6 +54,21 @@ pub struct AgentMountRequest {
     pub shared_dir: QemuSharedDir,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AgentUnmountRequest {
+    pub tag: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AgentDriveMountRequest {
+    pub drive: QemuDriveMount,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AgentDriveUnmountRequest {
+    pub serial: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AgentRunRequest {
     pub app: String,
@@ -57,6 +84,32 @@ pub struct AgentLayoutRequest {
     pub layout: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AgentTimeSyncRequest {
+    pub secs: u64,
+    pub nsecs: u32,
+}
+
+// Sent periodically by the guest agent, so the host can resize the VM.
+// "some_avg10" is the guest's PSI memory "some" average over the last 10
+// seconds, as a percentage.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AgentMemoryPressure {
+    pub total_kb: u64,
+    pub available_kb: u64,
+    pub some_avg10: f32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AgentPing {
+    pub seq: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AgentPong {
+    pub seq: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AgentAppExitCode {
     pub code: i32,
@@ -67,42 +120,179 @@ pub enum AgentMessage {
     AgentReady(AgentReady),
     AgentAck(AgentAck),
     AgentMountRequest(AgentMountRequest),
+    AgentUnmountRequest(AgentUnmountRequest),
+    AgentDriveMountRequest(AgentDriveMountRequest),
+    AgentDriveUnmountRequest(AgentDriveUnmountRequest),
     AgentRunRequest(AgentRunRequest),
     AgentLayoutRequest(AgentLayoutRequest),
+    AgentPauseNotification,
+    AgentTimeSyncRequest(AgentTimeSyncRequest),
     AgentAppExitCode(AgentAppExitCode),
+    AgentMemoryPressure(AgentMemoryPressure),
+    AgentPing(AgentPing),
+    AgentPong(AgentPong),
     AgentClosed,
     ClipboardEvent(ClipboardEvent),
     DbusNotification(DbusNotification),
     DbusNotificationClosed(DbusNotificationClosed),
 }
 
+// A message read from the agent by AgentReader::read.
+enum AgentIncoming {
+    Ack(i32),
+    Message(String),
+    Closed,
+}
+
+struct AgentReader {
+    reader: BufReader<UnixStream>,
+    pending: Vec<u8>,
+}
+
+impl AgentReader {
+    // A message partially read when the timeout expires is kept, and
+    // completed by the next call. It's only decoded once complete, as the
+    // timeout may split a multi-byte character.
+    fn read(&mut self) -> Result<AgentIncoming, String> {
+        match self.reader.read_until(b'\n', &mut self.pending) {
+            Ok(_) => (),
+            Err(ref err)
+                if err.kind() == std::io::ErrorKind::WouldBlock
+                    || err.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                return Err(AGENT_TIMEOUT.to_string())
+            }
+            Err(err) => return Err(err.to_string()),
+        }
+
+        let data = mem::take(&mut self.pending);
+        if data.is_empty() {
+            return Ok(AgentIncoming::Closed);
+        }
+        let data = String::from_utf8(data).map_err(|err| err.to_string())?;
+        match serde_json::from_str(&data) {
+            Ok(AgentMessage::AgentAck(msg)) => Ok(AgentIncoming::Ack(msg.status)),
+            _ => Ok(AgentIncoming::Message(data)),
+        }
+    }
+}
+
+#[derive(Default)]
+struct AgentQueues {
+    acks: VecDeque<i32>,
+    messages: VecDeque<String>,
+    // Acks to requests whose wait_ack timed out, which are dropped when
+    // they eventually arrive instead of being taken as the reply to the
+    // next request.
+    abandoned_acks: usize,
+}
+
+impl AgentQueues {
+    fn pop_ack(&mut self) -> Option<i32> {
+        while self.abandoned_acks > 0 && self.acks.pop_front().is_some() {
+            self.abandoned_acks -= 1;
+        }
+        self.acks.pop_front()
+    }
+}
+
+// The receiving side of the agent connection, shared by every clone of an
+// AgentHost. Whichever clone is waiting reads from the socket, queueing
+// what it isn't waiting for, so acks reach wait_ack even while another
+// thread is blocked in get_event, and unsolicited messages arriving while
+// an ack is awaited are kept for get_event.
+struct AgentReceiver {
+    reader: Mutex<AgentReader>,
+    queues: Mutex<AgentQueues>,
+    queued: Condvar,
+}
+
+impl AgentReceiver {
+    fn next<T, F>(&self, take: F, closed: fn() -> Result<T, String>) -> Result<T, String>
+    where
+        F: Fn(&mut AgentQueues) -> Option<T>,
+    {
+        let mut queues = self.queues.lock().unwrap();
+
+        loop {
+            if let Some(item) = take(&mut queues) {
+                return Ok(item);
+            }
+
+            let mut reader = match self.reader.try_lock() {
+                Ok(reader) => reader,
+                Err(TryLockError::Poisoned(err)) => err.into_inner(),
+                Err(TryLockError::WouldBlock) => {
+                    // Woken up once the current reader is done, whether
+                    // or not it got something for us.
+                    queues = self.queued.wait(queues).unwrap();
+                    continue;
+                }
+            };
+            drop(queues);
+            let incoming = reader.read();
+            drop(reader);
+
+            queues = self.queues.lock().unwrap();
+            self.queued.notify_all();
+            match incoming? {
+                AgentIncoming::Ack(status) => queues.acks.push_back(status),
+                AgentIncoming::Message(data) => queues.messages.push_back(data),
+                AgentIncoming::Closed => return closed(),
+            }
+        }
+    }
+}
+
 pub struct AgentHost {
     stream: UnixStream,
-    reader: BufReader<UnixStream>,
+    receiver: Arc<AgentReceiver>,
 }
 
 impl AgentHost {
     pub fn new(sockpath: String) -> Result<AgentHost, String> {
         let stream = open_socket(sockpath).map_err(|err| err.to_string())?;
         let reader = BufReader::new(stream.try_clone().map_err(|err| err.to_string())?);
-
-        Ok(AgentHost { stream, reader })
+        let receiver = AgentReceiver {
+            reader: Mutex::new(AgentReader {
+                reader,
+                pending: Vec::new(),
+            }),
+            queues: Mutex::new(AgentQueues::default()),
+            queued: Condvar::new(),
+        };
+
+        Ok(AgentHost {
+            stream,
+            receiver: Arc::new(receiver),
+        })
     }
 
+    // The clone shares the receiving side with this AgentHost, so either
+    // can wait for acks while the other waits for events.
     pub fn try_clone(&mut self) -> Result<AgentHost, std::io::Error> {
         let stream = self.stream.try_clone()?;
-        let reader = BufReader::new(stream.try_clone()?);
 
-        Ok(AgentHost { stream, reader })
+        Ok(AgentHost {
+            stream,
+            receiver: self.receiver.clone(),
+        })
     }
 
-    pub fn read_message(&mut self) -> Result<String, String> {
-        let mut line = String::new();
+    // Makes reads fail with AGENT_TIMEOUT if nothing arrives from the agent
+    // within "timeout", so a hung guest can't block the host forever. The
+    // timeout applies to every clone of this AgentHost.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), String> {
+        self.stream
+            .set_read_timeout(timeout)
+            .map_err(|err| err.to_string())
+    }
 
-        match self.reader.read_line(&mut line) {
-            Ok(_) => Ok(line),
-            Err(err) => Err(err.to_string()),
-        }
+    // Returns the next message other than an ack, or an empty string if
+    // the agent has closed the connection.
+    pub fn read_message(&mut self) -> Result<String, String> {
+        self.receiver
+            .next(|queues| queues.messages.pop_front(), || Ok(String::new()))
     }
 
     pub fn wait_handshake(&mut self) -> Result<AgentReady, String> {
@@ -126,13 +316,18 @@ impl AgentHost {
         self.send_message(&msg).map_err(|err| err.to_string())
     }
 
+    // Messages arriving before the ack are left for get_event.
     pub fn wait_ack(&mut self) -> Result<i32, String> {
-        let data = self.read_message()?;
-
-        match serde_json::from_str(&data).map_err(|err| err.to_string())? {
-            AgentMessage::AgentAck(msg) => Ok(msg.status),
-            _ => Err("Protocol error".to_string()),
+        let result = self.receiver.next(
+            |queues| queues.pop_ack(),
+            || Err("agent closed the connection".to_string()),
+        );
+        if let Err(ref err) = result {
+            if err == AGENT_TIMEOUT {
+                self.receiver.queues.lock().unwrap().abandoned_acks += 1;
+            }
         }
+        result
     }
 
     pub fn get_event(&mut self) -> Result<AgentMessage, String> {
@@ -143,6 +338,10 @@ impl AgentHost {
         } else {
             match serde_json::from_str(&data).map_err(|err| err.to_string())? {
                 AgentMessage::AgentAppExitCode(msg) => Ok(AgentMessage::AgentAppExitCode(msg)),
+                AgentMessage::AgentMemoryPressure(msg) => {
+                    Ok(AgentMessage::AgentMemoryPressure(msg))
+                }
+                AgentMessage::AgentPong(msg) => Ok(AgentMessage::AgentPong(msg)),
                 AgentMessage::ClipboardEvent(msg) => Ok(AgentMessage::ClipboardEvent(msg)),
                 AgentMessage::DbusNotification(msg) => Ok(AgentMessage::DbusNotification(msg)),
                 AgentMessage::DbusNotificationClosed(msg) => {
@@ -153,6 +352,15 @@ impl AgentHost {
         }
     }
 
+    // The agent replies with an AgentPong carrying the same "seq", which
+    // is returned by get_event.
+    pub fn send_ping(&mut self, seq: u64) -> Result<(), String> {
+        let ping = AgentMessage::AgentPing(AgentPing { seq });
+        let mut msg = serde_json::to_string(&ping).map_err(|err| err.to_string())?;
+        msg.push('\n');
+        self.send_message(&msg).map_err(|err| err.to_string())
+    }
+
     pub fn send_clipboard_event(&mut self, data: String) -> Result<(), String> {
         let cbe = AgentMessage::ClipboardEvent(ClipboardEvent { data });
         let mut msg = serde_json::to_string(&cbe).map_err(|err| err.to_string())?;
@@ -175,6 +383,30 @@ impl AgentHost {
         self.wait_ack()
     }
 
+    pub fn request_unmount(&mut self, tag: String) -> Result<i32, String> {
+        let ur = AgentMessage::AgentUnmountRequest(AgentUnmountRequest { tag });
+        let mut msg = serde_json::to_string(&ur).map_err(|err| err.to_string())?;
+        msg.push('\n');
+        self.send_message(&msg).map_err(|err| err.to_string())?;
+        self.wait_ack()
+    }
+
+    pub fn request_drive_mount(&mut self, drive: QemuDriveMount) -> Result<i32, String> {
+        let dmr = AgentMessage::AgentDriveMountRequest(AgentDriveMountRequest { drive });
+        let mut msg = serde_json::to_string(&dmr).map_err(|err| err.to_string())?;
+        msg.push('\n');
+        self.send_message(&msg).map_err(|err| err.to_string())?;
+        self.wait_ack()
+    }
+
+    pub fn request_drive_unmount(&mut self, serial: String) -> Result<i32, String> {
+        let dur = AgentMessage::AgentDriveUnmountRequest(AgentDriveUnmountRequest { serial });
+        let mut msg = serde_json::to_string(&dur).map_err(|err| err.to_string())?;
+        msg.push('\n');
+        self.send_message(&msg).map_err(|err| err.to_string())?;
+        self.wait_ack()
+    }
+
     pub fn request_layout(&mut self, layout: String) -> Result<i32, String> {
         let lr = AgentMessage::AgentLayoutRequest(AgentLayoutRequest { layout });
         let mut msg = serde_json::to_string(&lr).map_err(|err| err.to_string())?;
@@ -183,6 +415,30 @@ impl AgentHost {
         self.wait_ack()
     }
 
+    // Tells the agent the VM is about to be paused.
+    pub fn notify_pause(&mut self) -> Result<i32, String> {
+        let pn = AgentMessage::AgentPauseNotification;
+        let mut msg = serde_json::to_string(&pn).map_err(|err| err.to_string())?;
+        msg.push('\n');
+        self.send_message(&msg).map_err(|err| err.to_string())?;
+        self.wait_ack()
+    }
+
+    // Asks the agent to set the guest clock to the host's current time.
+    pub fn request_time_sync(&mut self) -> Result<i32, String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|err| err.to_string())?;
+        let tsr = AgentMessage::AgentTimeSyncRequest(AgentTimeSyncRequest {
+            secs: now.as_secs(),
+            nsecs: now.subsec_nanos(),
+        });
+        let mut msg = serde_json::to_string(&tsr).map_err(|err| err.to_string())?;
+        msg.push('\n');
+        self.send_message(&msg).map_err(|err| err.to_string())?;
+        self.wait_ack()
+    }
+
     pub fn request_run(
         &mut self,
         app: String,
@@ -217,6 +473,66 @@ impl AgentHost {
     }
 }
 
+// Tracks the agent's responsiveness by pinging it periodically and
+// matching the pongs handed over from the event loop. Only one ping is in
+// flight at a time, so a hung agent isn't flooded.
+pub struct Heartbeat {
+    timeout: Duration,
+    next_seq: u64,
+    in_flight: Option<(u64, Instant)>,
+    last_rtt: Option<Duration>,
+}
+
+impl Heartbeat {
+    // The agent is considered unresponsive once a ping has gone without
+    // a pong for "timeout".
+    pub fn new(timeout: Duration) -> Heartbeat {
+        Heartbeat {
+            timeout,
+            next_seq: 0,
+            in_flight: None,
+            last_rtt: None,
+        }
+    }
+
+    pub fn ping(&mut self, agent: &mut AgentHost) -> Result<(), String> {
+        if self.in_flight.is_some() {
+            return Ok(());
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        agent.send_ping(seq)?;
+        self.in_flight = Some((seq, Instant::now()));
+        Ok(())
+    }
+
+    // Records a pong received from the agent, returning the round-trip
+    // time if it answers the ping in flight.
+    pub fn pong(&mut self, pong: &AgentPong) -> Option<Duration> {
+        match self.in_flight {
+            Some((seq, sent)) if seq == pong.seq => {
+                let rtt = sent.elapsed();
+                self.in_flight = None;
+                self.last_rtt = Some(rtt);
+                Some(rtt)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn get_latency(&self) -> Option<Duration> {
+        self.last_rtt
+    }
+
+    pub fn is_unresponsive(&self) -> bool {
+        match self.in_flight {
+            Some((_, sent)) => sent.elapsed() > self.timeout,
+            None => false,
+        }
+    }
+}
+
 pub struct AgentGuest {
     file: File,
     reader: BufReader<File>,
@@ -281,6 +597,20 @@ impl AgentGuest {
         self.send_message(&data).map_err(|err| err.to_string())
     }
 
+    pub fn send_pong(&mut self, seq: u64) -> Result<(), String> {
+        let pong = AgentMessage::AgentPong(AgentPong { seq });
+        let mut data = serde_json::to_string(&pong).map_err(|err| err.to_string())?;
+        data.push('\n');
+        self.send_message(&data).map_err(|err| err.to_string())
+    }
+
+    pub fn send_memory_pressure(&mut self, mp: AgentMemoryPressure) -> Result<(), String> {
+        let mp = AgentMessage::AgentMemoryPressure(mp);
+        let mut data = serde_json::to_string(&mp).map_err(|err| err.to_string())?;
+        data.push('\n');
+        self.send_message(&data).map_err(|err| err.to_string())
+    }
+
     pub fn send_clipboard_event(&mut self, c: ClipboardEvent) -> Result<(), String> {
         let ce = AgentMessage::ClipboardEvent(c);
         let mut data = serde_json::to_string(&ce).map_err(|err| err.to_string())?;
@@ -310,3 +640,54 @@ impl AgentGuest {
         serde_json::from_str(&data).map_err(|err| err.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    fn ack(status: i32) -> String {
+        let ack = AgentMessage::AgentAck(AgentAck { status });
+        format!("{}\n", serde_json::to_string(&ack).unwrap())
+    }
+
+    #[test]
+    fn late_ack_is_not_taken_for_the_next_one() {
+        let path =
+            std::env::temp_dir().join(format!("flatkvm-agent-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let (send, recv) = channel::<String>();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            for msg in recv.iter() {
+                stream.write_all(msg.as_bytes()).unwrap();
+            }
+        });
+
+        let mut agent = AgentHost::new(path.to_string_lossy().to_string()).unwrap();
+        agent
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        assert_eq!(agent.wait_ack(), Err(AGENT_TIMEOUT.to_string()));
+
+        // The ack to the abandoned request arrives along with the next one,
+        // after an unsolicited message.
+        let pong = AgentMessage::AgentPong(AgentPong { seq: 7 });
+        send.send(format!("{}\n", serde_json::to_string(&pong).unwrap()))
+            .unwrap();
+        send.send(ack(1)).unwrap();
+        send.send(ack(2)).unwrap();
+        assert_eq!(agent.wait_ack(), Ok(2));
+        match agent.get_event() {
+            Ok(AgentMessage::AgentPong(pong)) => assert_eq!(pong.seq, 7),
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        drop(send);
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,293 @@
+// flatkvm-qemu
+// Copyright (C) 2019  Sergio Lopez <slp@sinrega.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::ffi::OsStr;
+use std::fs::{self, OpenOptions};
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const CONTROLLERS: &[&str] = &["cpu", "memory", "io"];
+// Leaf the launcher moves itself to, so controllers can be enabled for the
+// cgroups of its VMs.
+const LAUNCHER_CGROUP: &str = "flatkvm-launcher";
+
+#[derive(Clone, Debug)]
+struct IoMax {
+    device: String,
+    rbps: Option<u64>,
+    wbps: Option<u64>,
+    riops: Option<u64>,
+    wiops: Option<u64>,
+}
+
+impl IoMax {
+    fn to_line(&self) -> Result<String, String> {
+        let rdev = fs::metadata(&self.device)
+            .map_err(|err| format!("{}: {}", self.device, err))?
+            .rdev();
+        let mut line = format!("{}:{}", libc::major(rdev), libc::minor(rdev));
+        for (key, value) in &[
+            ("rbps", self.rbps),
+            ("wbps", self.wbps),
+            ("riops", self.riops),
+            ("wiops", self.wiops),
+        ] {
+            match value {
+                Some(value) => line.push_str(&format!(" {}={}", key, value)),
+                None => line.push_str(&format!(" {}=max", key)),
+            }
+        }
+        Ok(line)
+    }
+}
+
+// Limits for the cgroup holding a VM, unlimited unless set.
+#[derive(Clone, Debug, Default)]
+pub struct CgroupLimits {
+    cpu_max: Option<(u64, u64)>,
+    cpu_weight: Option<u64>,
+    memory_max_mb: Option<u64>,
+    io_max: Vec<IoMax>,
+}
+
+impl CgroupLimits {
+    pub fn new() -> CgroupLimits {
+        CgroupLimits::default()
+    }
+
+    // Allows the VM "quota_us" of CPU time every "period_us", e.g. 200000
+    // every 100000 for two full host CPUs.
+    pub fn cpu_max(mut self, quota_us: u64, period_us: u64) -> Self {
+        self.cpu_max = Some((quota_us, period_us));
+        self
+    }
+
+    // Relative share of CPU time under contention, from 1 to 10000. The
+    // default weight of other cgroups is 100.
+    pub fn cpu_weight(mut self, weight: u64) -> Self {
+        self.cpu_weight = Some(weight);
+        self
+    }
+
+    // Caps the memory of QEMU and its helpers, which must leave room for
+    // QEMU's own overhead on top of the guest RAM.
+    pub fn memory_max_mb(mut self, mb: u64) -> Self {
+        self.memory_max_mb = Some(mb);
+        self
+    }
+
+    // Throttles the IO to the block device at "device" (e.g. /dev/nvme0n1)
+    // in bytes and operations per second.
+    pub fn io_max(
+        mut self,
+        device: String,
+        rbps: Option<u64>,
+        wbps: Option<u64>,
+        riops: Option<u64>,
+        wiops: Option<u64>,
+    ) -> Self {
+        self.io_max.push(IoMax {
+            device,
+            rbps,
+            wbps,
+            riops,
+            wiops,
+        });
+        self
+    }
+}
+
+// A cgroup v2 leaf holding the processes of a VM.
+#[derive(Clone, Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+fn write_file(path: &Path, data: &str) -> Result<(), String> {
+    fs::write(path, data).map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+fn read_file(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+fn has_controllers(list: &str) -> bool {
+    CONTROLLERS
+        .iter()
+        .all(|controller| list.split_whitespace().any(|c| c == *controller))
+}
+
+// Enables the controllers for the children of "parent", which must have
+// been delegated to the user. As a cgroup holding processes can't enable
+// controllers for its children, the launcher first moves itself to a leaf
+// of its own if it's in "parent".
+fn enable_controllers(parent: &Path) -> Result<(), String> {
+    if !has_controllers(&read_file(&parent.join("cgroup.controllers"))?) {
+        return Err(format!(
+            "{}: the {} controllers aren't delegated to this cgroup",
+            parent.display(),
+            CONTROLLERS.join(", ")
+        ));
+    }
+    let subtree_control = parent.join("cgroup.subtree_control");
+    if has_controllers(&read_file(&subtree_control)?) {
+        return Ok(());
+    }
+
+    if Cgroup::current_path()? == parent {
+        let leaf = parent.join(LAUNCHER_CGROUP);
+        match fs::create_dir(&leaf) {
+            Ok(_) => (),
+            Err(ref err) if err.kind() == ErrorKind::AlreadyExists => (),
+            Err(err) => return Err(format!("{}: {}", leaf.display(), err)),
+        }
+        write_file(&leaf.join("cgroup.procs"), "0")?;
+    }
+
+    let controllers: Vec<String> = CONTROLLERS.iter().map(|c| format!("+{}", c)).collect();
+    match fs::write(&subtree_control, controllers.join(" ")) {
+        Ok(_) => Ok(()),
+        Err(ref err) if err.kind() == ErrorKind::PermissionDenied => Err(format!(
+            "{}: the cgroup isn't delegated to this user",
+            parent.display()
+        )),
+        Err(ref err) if err.raw_os_error() == Some(libc::EBUSY) => Err(format!(
+            "{}: the cgroup is shared with other processes",
+            parent.display()
+        )),
+        Err(err) => Err(format!("{}: {}", subtree_control.display(), err)),
+    }
+}
+
+impl Cgroup {
+    // Returns the cgroup of the current process.
+    pub fn current_path() -> Result<PathBuf, String> {
+        let data = fs::read_to_string("/proc/self/cgroup")
+            .map_err(|err| format!("/proc/self/cgroup: {}", err))?;
+        for line in data.lines() {
+            if let Some(path) = line.strip_prefix("0::") {
+                return Ok(Path::new(CGROUP_ROOT).join(path.trim_start_matches('/')));
+            }
+        }
+        Err("not running in a cgroup v2 hierarchy".to_string())
+    }
+
+    // Where the cgroup for the VM "name" goes: under the cgroup the
+    // launcher was started in, which must be delegated to the user, e.g.
+    // by running it with "systemd-run --user --scope -p Delegate=yes".
+    pub fn path_for(name: &str) -> Result<PathBuf, String> {
+        let mut base = Cgroup::current_path()?;
+        if base.file_name() == Some(OsStr::new(LAUNCHER_CGROUP)) {
+            base.pop();
+        }
+        if base == Path::new(CGROUP_ROOT) {
+            return Err("can't create a cgroup under the root cgroup".to_string());
+        }
+        Ok(base.join(format!("flatkvm-{}-{}", name, std::process::id())))
+    }
+
+    pub fn open(path: PathBuf) -> Cgroup {
+        Cgroup { path }
+    }
+
+    // Creates the cgroup at "path", or reuses it if it already exists, and
+    // applies "limits" to it.
+    pub fn create(path: PathBuf, limits: &CgroupLimits) -> Result<Cgroup, String> {
+        if let Some(parent) = path.parent() {
+            enable_controllers(parent)?;
+        }
+        match fs::create_dir(&path) {
+            Ok(_) => (),
+            Err(ref err) if err.kind() == ErrorKind::AlreadyExists => (),
+            Err(err) => return Err(format!("{}: {}", path.display(), err)),
+        }
+
+        let cgroup = Cgroup { path };
+        if let Err(err) = cgroup.apply(limits) {
+            let _ = cgroup.remove();
+            return Err(err);
+        }
+        Ok(cgroup)
+    }
+
+    fn apply(&self, limits: &CgroupLimits) -> Result<(), String> {
+        if let Some((quota_us, period_us)) = limits.cpu_max {
+            write_file(
+                &self.path.join("cpu.max"),
+                &format!("{} {}", quota_us, period_us),
+            )?;
+        }
+        if let Some(weight) = limits.cpu_weight {
+            write_file(&self.path.join("cpu.weight"), &weight.to_string())?;
+        }
+        if let Some(mb) = limits.memory_max_mb {
+            write_file(&self.path.join("memory.max"), &(mb << 20).to_string())?;
+        }
+        for io_max in &limits.io_max {
+            write_file(&self.path.join("io.max"), &io_max.to_line()?)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    // The file a process can write "0" to in order to move itself into
+    // the cgroup, which is how QEMU is placed in it before running.
+    pub fn procs_path(&self) -> PathBuf {
+        self.path.join("cgroup.procs")
+    }
+
+    // Has the process spawned by "command" move itself into the cgroup
+    // before exec, so none of its memory or CPU time escapes the limits.
+    pub(crate) fn enter_on_spawn(&self, command: &mut Command) -> Result<(), String> {
+        let path = self.procs_path();
+        let procs = OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+
+        unsafe {
+            command.pre_exec(move || {
+                let fd = procs.as_raw_fd();
+                if libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    pub fn add_pid(&self, pid: u32) -> Result<(), String> {
+        write_file(&self.procs_path(), &pid.to_string())
+    }
+
+    // Removes the cgroup, which must no longer hold any process.
+    pub fn remove(&self) -> Result<(), String> {
+        match fs::remove_dir(&self.path) {
+            Ok(_) => Ok(()),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(format!("{}: {}", self.path.display(), err)),
+        }
+    }
+}
@@ -0,0 +1,510 @@
+// flatkvm-qemu
+// Copyright (C) 2019  Sergio Lopez <slp@sinrega.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use crate::util::{check_secret_file, qemu_opts};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::os::unix::io::RawFd;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum QemuDriveFormat {
+    Raw,
+    Qcow2,
+}
+
+impl QemuDriveFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            QemuDriveFormat::Raw => "raw",
+            QemuDriveFormat::Qcow2 => "qcow2",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum QemuDriveCache {
+    None,
+    Writeback,
+    Writethrough,
+    Directsync,
+    Unsafe,
+}
+
+impl QemuDriveCache {
+    fn direct(self) -> bool {
+        matches!(self, QemuDriveCache::None | QemuDriveCache::Directsync)
+    }
+
+    fn no_flush(self) -> bool {
+        self == QemuDriveCache::Unsafe
+    }
+
+    fn write_cache(self) -> bool {
+        !matches!(
+            self,
+            QemuDriveCache::Writethrough | QemuDriveCache::Directsync
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum QemuDriveAio {
+    Threads,
+    Native,
+    IoUring,
+}
+
+impl QemuDriveAio {
+    fn as_str(self) -> &'static str {
+        match self {
+            QemuDriveAio::Threads => "threads",
+            QemuDriveAio::Native => "native",
+            QemuDriveAio::IoUring => "io_uring",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum QemuDriveDiscard {
+    Ignore,
+    Unmap,
+}
+
+impl QemuDriveDiscard {
+    fn as_str(self) -> &'static str {
+        match self {
+            QemuDriveDiscard::Ignore => "ignore",
+            QemuDriveDiscard::Unmap => "unmap",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum QemuDriveDetectZeroes {
+    Off,
+    On,
+    Unmap,
+}
+
+impl QemuDriveDetectZeroes {
+    fn as_str(self) -> &'static str {
+        match self {
+            QemuDriveDetectZeroes::Off => "off",
+            QemuDriveDetectZeroes::On => "on",
+            QemuDriveDetectZeroes::Unmap => "unmap",
+        }
+    }
+}
+
+// Source of the LUKS passphrase of an encrypted drive. QEMU reads it
+// through a secret object, so it never shows up in the command line.
+#[derive(Clone, Debug)]
+pub enum QemuDriveSecret {
+    File(String),
+    Fd(RawFd),
+}
+
+impl QemuDriveSecret {
+    fn path(&self) -> String {
+        match self {
+            QemuDriveSecret::File(path) => path.to_string(),
+            QemuDriveSecret::Fd(fd) => format!("/dev/fd/{}", fd),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct QemuDrive {
+    path: String,
+    format: QemuDriveFormat,
+    cache: QemuDriveCache,
+    aio: QemuDriveAio,
+    discard: QemuDriveDiscard,
+    detect_zeroes: QemuDriveDetectZeroes,
+    readonly: bool,
+    secret: Option<QemuDriveSecret>,
+}
+
+impl QemuDrive {
+    pub fn new(path: String) -> QemuDrive {
+        QemuDrive {
+            path,
+            format: QemuDriveFormat::Qcow2,
+            cache: QemuDriveCache::Writeback,
+            aio: QemuDriveAio::Threads,
+            discard: QemuDriveDiscard::Ignore,
+            detect_zeroes: QemuDriveDetectZeroes::Off,
+            readonly: false,
+            secret: None,
+        }
+    }
+
+    pub fn format(mut self, format: QemuDriveFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn cache(mut self, cache: QemuDriveCache) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    pub fn aio(mut self, aio: QemuDriveAio) -> Self {
+        self.aio = aio;
+        self
+    }
+
+    pub fn discard(mut self, discard: QemuDriveDiscard) -> Self {
+        self.discard = discard;
+        self
+    }
+
+    pub fn detect_zeroes(mut self, detect_zeroes: QemuDriveDetectZeroes) -> Self {
+        self.detect_zeroes = detect_zeroes;
+        self
+    }
+
+    pub fn readonly(mut self, readonly: bool) -> Self {
+        self.readonly = readonly;
+        self
+    }
+
+    // Opens the drive as a LUKS-encrypted qcow2 image.
+    pub fn encrypted(mut self, secret: QemuDriveSecret) -> Self {
+        self.secret = Some(secret);
+        self
+    }
+
+    pub fn get_secret(&self) -> Option<&QemuDriveSecret> {
+        self.secret.as_ref()
+    }
+
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn get_format(&self) -> QemuDriveFormat {
+        self.format
+    }
+
+    pub fn is_readonly(&self) -> bool {
+        self.readonly
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.aio == QemuDriveAio::Native && !self.cache.direct() {
+            return Err(format!(
+                "{}: aio=native requires cache mode none or directsync",
+                self.path
+            ));
+        }
+        if self.detect_zeroes == QemuDriveDetectZeroes::Unmap
+            && self.discard != QemuDriveDiscard::Unmap
+        {
+            return Err(format!(
+                "{}: detect-zeroes=unmap requires discard=unmap",
+                self.path
+            ));
+        }
+        match &self.secret {
+            Some(QemuDriveSecret::File(path)) => check_secret_file(path)?,
+            Some(QemuDriveSecret::Fd(_)) => (),
+            None => return Ok(()),
+        }
+        if self.format != QemuDriveFormat::Qcow2 {
+            return Err(format!("{}: only qcow2 drives can be encrypted", self.path));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn secret_object(&self, node_name: &str) -> Option<Value> {
+        self.secret.as_ref().map(|secret| {
+            json!({
+                "qom-type": "secret",
+                "id": format!("{}-secret", node_name),
+                "file": secret.path(),
+            })
+        })
+    }
+
+    fn file_node(&self, node_name: &str, filename: &str, readonly: bool) -> Value {
+        json!({
+            "driver": "file",
+            "node-name": node_name,
+            "filename": filename,
+            "aio": self.aio.as_str(),
+            "cache": {
+                "direct": self.cache.direct(),
+                "no-flush": self.cache.no_flush(),
+            },
+            "discard": self.discard.as_str(),
+            "read-only": readonly,
+        })
+    }
+
+    // Overlays live in the overlay dir, which is often pointed to a tmpfs
+    // to keep their writes off the disk. As tmpfs has no O_DIRECT, they
+    // always go through the host page cache.
+    fn overlay_file_node(&self, node_name: &str, filename: &str) -> Value {
+        let aio = match self.aio {
+            QemuDriveAio::Native => QemuDriveAio::Threads,
+            aio => aio,
+        };
+        json!({
+            "driver": "file",
+            "node-name": node_name,
+            "filename": filename,
+            "aio": aio.as_str(),
+            "cache": {
+                "direct": false,
+                "no-flush": self.cache.no_flush(),
+            },
+            "discard": self.discard.as_str(),
+            "read-only": false,
+        })
+    }
+
+    // Builds the chain of block nodes for this drive, in the order they
+    // must be added, with "node_name" being the name of the top node. If
+    // "overlay" is set, the image is opened read-only as the backing file
+    // of a qcow2 overlay at that path, which receives all the writes.
+    pub(crate) fn blockdev_nodes(&self, node_name: &str, overlay: Option<&str>) -> Vec<Value> {
+        let file = format!("{}-file", node_name);
+
+        match overlay {
+            Some(overlay) => {
+                let base = format!("{}-base", node_name);
+                let base_file = format!("{}-file", base);
+                vec![
+                    self.file_node(&base_file, &self.path, true),
+                    json!({
+                        "driver": self.format.as_str(),
+                        "node-name": base,
+                        "file": base_file,
+                        "read-only": true,
+                    }),
+                    self.overlay_file_node(&file, overlay),
+                    json!({
+                        "driver": "qcow2",
+                        "node-name": node_name,
+                        "file": file,
+                        "backing": base,
+                        "discard": self.discard.as_str(),
+                        "detect-zeroes": self.detect_zeroes.as_str(),
+                    }),
+                ]
+            }
+            None => {
+                let mut node = json!({
+                    "driver": self.format.as_str(),
+                    "node-name": node_name,
+                    "file": file,
+                    "discard": self.discard.as_str(),
+                    "detect-zeroes": self.detect_zeroes.as_str(),
+                    "read-only": self.readonly,
+                });
+                if self.secret.is_some() {
+                    node["encrypt"] = json!({
+                        "format": "luks",
+                        "key-secret": format!("{}-secret", node_name),
+                    });
+                }
+                vec![self.file_node(&file, &self.path, self.readonly), node]
+            }
+        }
+    }
+
+    pub(crate) fn device_props(
+        &self,
+        node_name: &str,
+        serial: Option<&str>,
+        iothread: Option<&str>,
+        num_queues: Option<u32>,
+    ) -> Value {
+        let mut props = json!({
+            "driver": "virtio-blk-pci",
+            "id": node_name,
+            "drive": node_name,
+            "write-cache": on_off(self.cache.write_cache()),
+        });
+        if let Some(serial) = serial {
+            props["serial"] = json!(serial);
+        }
+        if let Some(iothread) = iothread {
+            props["iothread"] = json!(iothread);
+        }
+        if let Some(num_queues) = num_queues {
+            props["num-queues"] = json!(num_queues);
+        }
+        props
+    }
+
+    pub(crate) fn usb_device_props(&self, node_name: &str, serial: &str) -> Value {
+        json!({
+            "driver": "usb-storage",
+            "id": node_name,
+            "drive": node_name,
+            "serial": serial,
+            "removable": true,
+        })
+    }
+
+    pub(crate) fn blockdev_args(&self, node_name: &str, overlay: Option<&str>) -> String {
+        let mut args = String::new();
+
+        if let Some(secret) = self.secret_object(node_name) {
+            args.push_str(&format!(" -object {}", qemu_opts(&secret, "qom-type")));
+        }
+        for node in self.blockdev_nodes(node_name, overlay) {
+            args.push_str(&format!(" -blockdev {}", qemu_opts(&node, "")));
+        }
+
+        args
+    }
+
+    pub(crate) fn device_args(
+        &self,
+        node_name: &str,
+        serial: Option<&str>,
+        iothread: Option<&str>,
+        num_queues: Option<u32>,
+    ) -> String {
+        let props = self.device_props(node_name, serial, iothread, num_queues);
+        format!(" -device {}", qemu_opts(&props, "driver"))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum QemuDriveBus {
+    #[default]
+    Virtio,
+    Usb,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum QemuDriveRole {
+    Data,
+    Scratch,
+    Cache,
+    Image,
+    Custom(String),
+}
+
+// Additional block device attached after the template and the data disk.
+// A scratch drive is a fresh, empty image created on each run and
+// discarded on cleanup.
+#[derive(Clone, Debug)]
+pub struct QemuExtraDrive {
+    drive: QemuDrive,
+    role: QemuDriveRole,
+    scratch_size_mb: Option<u64>,
+    volatile: bool,
+    mount_point: Option<String>,
+}
+
+impl QemuExtraDrive {
+    pub fn new(role: QemuDriveRole, drive: QemuDrive) -> QemuExtraDrive {
+        QemuExtraDrive {
+            drive,
+            role,
+            scratch_size_mb: None,
+            volatile: false,
+            mount_point: None,
+        }
+    }
+
+    pub fn scratch(role: QemuDriveRole, size_mb: u64) -> QemuExtraDrive {
+        QemuExtraDrive {
+            drive: QemuDrive::new(String::new()),
+            role,
+            scratch_size_mb: Some(size_mb),
+            volatile: false,
+            mount_point: None,
+        }
+    }
+
+    pub fn volatile(mut self, volatile: bool) -> Self {
+        self.volatile = volatile;
+        self
+    }
+
+    pub fn readonly(mut self, readonly: bool) -> Self {
+        self.drive = self.drive.readonly(readonly);
+        self
+    }
+
+    pub fn mount_point(mut self, mount_point: String) -> Self {
+        self.mount_point = Some(mount_point);
+        self
+    }
+
+    // Scratch images are formatted by the agent and dropped along with the
+    // VM, so they can be neither read-only nor volatile.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.scratch_size_mb.is_some() {
+            if self.drive.is_readonly() {
+                return Err("scratch drives can't be read-only".to_string());
+            }
+            if self.volatile {
+                return Err("scratch drives can't be volatile".to_string());
+            }
+            return Ok(());
+        }
+        self.drive.validate()
+    }
+
+    pub fn get_drive(&self) -> &QemuDrive {
+        &self.drive
+    }
+
+    pub fn get_role(&self) -> &QemuDriveRole {
+        &self.role
+    }
+
+    pub fn get_scratch_size_mb(&self) -> Option<u64> {
+        self.scratch_size_mb
+    }
+
+    pub fn is_volatile(&self) -> bool {
+        self.volatile
+    }
+
+    pub fn get_mount_point(&self) -> Option<&str> {
+        self.mount_point.as_deref()
+    }
+}
+
+// Describes a drive to the agent, which finds it in the guest through its
+// bus and serial (e.g. /dev/disk/by-id/virtio-<serial>). The mount point
+// of the data disk is chosen by the agent.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QemuDriveMount {
+    pub role: QemuDriveRole,
+    pub serial: String,
+    pub mount_point: String,
+    pub readonly: bool,
+    pub mkfs: bool,
+    #[serde(default)]
+    pub bus: QemuDriveBus,
+}
+
+fn on_off(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
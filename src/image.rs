@@ -0,0 +1,137 @@
+// flatkvm-qemu
+// Copyright (C) 2019  Sergio Lopez <slp@sinrega.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use crate::drive::QemuDriveFormat;
+use crate::util::check_secret_file;
+use std::fs;
+use std::process::{Command, Stdio};
+
+fn qemu_img(args: &[&str]) -> Result<(), String> {
+    let output = Command::new("qemu-img")
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|err| err.to_string())?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "qemu-img {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+pub fn create_overlay(
+    backing: &str,
+    backing_format: QemuDriveFormat,
+    path: &str,
+) -> Result<(), String> {
+    let backing = fs::canonicalize(backing).map_err(|err| format!("{}: {}", backing, err))?;
+    let backing = match backing.to_str() {
+        Some(backing) => backing.to_string(),
+        None => return Err("invalid backing file path".to_string()),
+    };
+
+    qemu_img(&[
+        "create",
+        "-q",
+        "-f",
+        "qcow2",
+        "-F",
+        backing_format.as_str(),
+        "-b",
+        &backing,
+        path,
+    ])
+}
+
+pub fn create_image(path: &str, format: QemuDriveFormat, size_mb: u64) -> Result<(), String> {
+    qemu_img(&[
+        "create",
+        "-q",
+        "-f",
+        format.as_str(),
+        path,
+        &format!("{}M", size_mb),
+    ])
+}
+
+// Creates a LUKS-encrypted qcow2 image, using the contents of "key_file"
+// as passphrase.
+pub fn create_encrypted_image(path: &str, size_mb: u64, key_file: &str) -> Result<(), String> {
+    check_secret_file(key_file)?;
+
+    qemu_img(&[
+        "create",
+        "-q",
+        "--object",
+        &format!("secret,id=sec0,file={}", key_file),
+        "-f",
+        "qcow2",
+        "-o",
+        "encrypt.format=luks,encrypt.key-secret=sec0",
+        path,
+        &format!("{}M", size_mb),
+    ])
+}
+
+// Replaces the passphrase of a LUKS-encrypted qcow2 image. A keyslot with
+// the new passphrase is added first, and only then the keyslots matching
+// the old one are erased, so the image is never left without a valid key.
+pub fn rekey_encrypted_image(
+    path: &str,
+    old_key_file: &str,
+    new_key_file: &str,
+) -> Result<(), String> {
+    check_secret_file(old_key_file)?;
+    check_secret_file(new_key_file)?;
+
+    let old_secret = format!("secret,id=oldsec,file={}", old_key_file);
+    let new_secret = format!("secret,id=newsec,file={}", new_key_file);
+
+    qemu_img(&[
+        "amend",
+        "--object",
+        &old_secret,
+        "--object",
+        &new_secret,
+        "--image-opts",
+        &format!(
+            "driver=qcow2,file.filename={},encrypt.key-secret=oldsec",
+            path
+        ),
+        "-o",
+        "encrypt.state=active,encrypt.new-secret=newsec",
+    ])?;
+
+    qemu_img(&[
+        "amend",
+        "--object",
+        &old_secret,
+        "--object",
+        &new_secret,
+        "--image-opts",
+        &format!(
+            "driver=qcow2,file.filename={},encrypt.key-secret=newsec",
+            path
+        ),
+        "-o",
+        "encrypt.state=inactive,encrypt.old-secret=oldsec",
+    ])
+}
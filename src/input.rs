@@ -0,0 +1,286 @@
+// flatkvm-qemu
+// Copyright (C) 2019  Sergio Lopez <slp@sinrega.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use crate::qmpconn::QmpConn;
+use serde_json::{json, Value};
+use std::thread;
+use std::time::Duration;
+
+// Absolute pointer coordinates are scaled by QEMU from this range to the
+// guest's screen size.
+pub const INPUT_ABS_MAX: i64 = 0x7fff;
+
+const KEY_HOLD_MS: u32 = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputButton {
+    Left,
+    Middle,
+    Right,
+    WheelUp,
+    WheelDown,
+    Side,
+    Extra,
+}
+
+impl InputButton {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InputButton::Left => "left",
+            InputButton::Middle => "middle",
+            InputButton::Right => "right",
+            InputButton::WheelUp => "wheel-up",
+            InputButton::WheelDown => "wheel-down",
+            InputButton::Side => "side",
+            InputButton::Extra => "extra",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputAxis {
+    X,
+    Y,
+}
+
+impl InputAxis {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InputAxis::X => "x",
+            InputAxis::Y => "y",
+        }
+    }
+}
+
+// An event for QmpConn::input_send_event. Keys are named by their QEMU
+// qcode, e.g. "a", "ret", "ctrl" or "alt".
+#[derive(Clone, Debug, PartialEq)]
+pub enum InputEvent {
+    Key { qcode: String, down: bool },
+    Button { button: InputButton, down: bool },
+    Rel { axis: InputAxis, value: i64 },
+    Abs { axis: InputAxis, value: i64 },
+}
+
+impl InputEvent {
+    pub(crate) fn to_json(&self) -> Value {
+        match self {
+            InputEvent::Key { qcode, down } => json!({
+                "type": "key",
+                "data": {
+                    "down": down,
+                    "key": { "type": "qcode", "data": qcode },
+                },
+            }),
+            InputEvent::Button { button, down } => json!({
+                "type": "btn",
+                "data": { "down": down, "button": button.as_str() },
+            }),
+            InputEvent::Rel { axis, value } => json!({
+                "type": "rel",
+                "data": { "axis": axis.as_str(), "value": value },
+            }),
+            InputEvent::Abs { axis, value } => json!({
+                "type": "abs",
+                "data": { "axis": axis.as_str(), "value": value },
+            }),
+        }
+    }
+}
+
+// Returns the qcode for "c" on a US layout and whether shift must be held
+// to type it.
+fn char_qcode(c: char) -> Option<(String, bool)> {
+    let unshifted = match c {
+        'a'..='z' | '0'..='9' => return Some((c.to_string(), false)),
+        'A'..='Z' => return Some((c.to_ascii_lowercase().to_string(), true)),
+        ' ' => "spc",
+        '\n' => "ret",
+        '\t' => "tab",
+        '-' => "minus",
+        '=' => "equal",
+        '[' => "bracket_left",
+        ']' => "bracket_right",
+        '\\' => "backslash",
+        ';' => "semicolon",
+        '\'' => "apostrophe",
+        '`' => "grave_accent",
+        ',' => "comma",
+        '.' => "dot",
+        '/' => "slash",
+        _ => "",
+    };
+    if !unshifted.is_empty() {
+        return Some((unshifted.to_string(), false));
+    }
+
+    let shifted = match c {
+        '!' => "1",
+        '@' => "2",
+        '#' => "3",
+        '$' => "4",
+        '%' => "5",
+        '^' => "6",
+        '&' => "7",
+        '*' => "8",
+        '(' => "9",
+        ')' => "0",
+        '_' => "minus",
+        '+' => "equal",
+        '{' => "bracket_left",
+        '}' => "bracket_right",
+        '|' => "backslash",
+        ':' => "semicolon",
+        '"' => "apostrophe",
+        '~' => "grave_accent",
+        '<' => "comma",
+        '>' => "dot",
+        '?' => "slash",
+        _ => return None,
+    };
+    Some((shifted.to_string(), true))
+}
+
+// Types "text" as if entered on a keyboard with a US layout. Characters
+// that can't be typed on it are rejected before anything is sent.
+pub fn type_string(qmp: &QmpConn, text: &str) -> Result<(), String> {
+    let mut keys = Vec::new();
+    for c in text.chars() {
+        match char_qcode(c) {
+            Some(key) => keys.push(key),
+            None => return Err(format!("can't type character {:?}", c)),
+        }
+    }
+
+    for (qcode, shift) in keys {
+        if shift {
+            qmp.send_key(&["shift", &qcode], Some(KEY_HOLD_MS))?;
+        } else {
+            qmp.send_key(&[&qcode], Some(KEY_HOLD_MS))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn send_ctrl_alt_del(qmp: &QmpConn) -> Result<(), String> {
+    qmp.send_key(&["ctrl", "alt", "delete"], None)
+}
+
+// Scales a position on a screen of "size" pixels to the absolute pointer
+// range.
+pub fn scale_abs(pos: u32, size: u32) -> i64 {
+    if size <= 1 {
+        return 0;
+    }
+    i64::from(pos.min(size - 1)) * INPUT_ABS_MAX / i64::from(size - 1)
+}
+
+// Moves the pointer to "x", "y" on a guest screen of "width" by "height"
+// pixels. Needs the VM to have an absolute pointing device, see
+// QemuRunner::tablet.
+pub fn move_to(qmp: &QmpConn, x: u32, y: u32, width: u32, height: u32) -> Result<(), String> {
+    qmp.input_send_event(&[
+        InputEvent::Abs {
+            axis: InputAxis::X,
+            value: scale_abs(x, width),
+        },
+        InputEvent::Abs {
+            axis: InputAxis::Y,
+            value: scale_abs(y, height),
+        },
+    ])
+}
+
+pub fn click(qmp: &QmpConn, button: InputButton) -> Result<(), String> {
+    qmp.input_send_event(&[InputEvent::Button { button, down: true }])?;
+    thread::sleep(Duration::from_millis(u64::from(KEY_HOLD_MS)));
+    qmp.input_send_event(&[InputEvent::Button {
+        button,
+        down: false,
+    }])
+}
+
+pub fn click_at(
+    qmp: &QmpConn,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    button: InputButton,
+) -> Result<(), String> {
+    move_to(qmp, x, y, width, height)?;
+    click(qmp, button)
+}
+
+// Scrolls by "steps" wheel clicks, up if positive, down if negative.
+pub fn scroll(qmp: &QmpConn, steps: i32) -> Result<(), String> {
+    if steps == 0 {
+        return Ok(());
+    }
+
+    let button = if steps > 0 {
+        InputButton::WheelUp
+    } else {
+        InputButton::WheelDown
+    };
+    for _ in 0..steps.unsigned_abs() {
+        click(qmp, button)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_chars_are_unshifted() {
+        assert_eq!(char_qcode('a'), Some(("a".to_string(), false)));
+        assert_eq!(char_qcode('7'), Some(("7".to_string(), false)));
+        assert_eq!(char_qcode(' '), Some(("spc".to_string(), false)));
+        assert_eq!(char_qcode('\n'), Some(("ret".to_string(), false)));
+        assert_eq!(char_qcode('/'), Some(("slash".to_string(), false)));
+    }
+
+    #[test]
+    fn shifted_chars_use_the_unshifted_key() {
+        assert_eq!(char_qcode('Q'), Some(("q".to_string(), true)));
+        assert_eq!(char_qcode('!'), Some(("1".to_string(), true)));
+        assert_eq!(char_qcode('_'), Some(("minus".to_string(), true)));
+        assert_eq!(char_qcode('?'), Some(("slash".to_string(), true)));
+    }
+
+    #[test]
+    fn chars_off_the_us_layout_are_rejected() {
+        assert_eq!(char_qcode('é'), None);
+        assert_eq!(char_qcode('€'), None);
+        assert_eq!(char_qcode('\r'), None);
+    }
+
+    #[test]
+    fn abs_positions_span_the_whole_range() {
+        assert_eq!(scale_abs(0, 1024), 0);
+        assert_eq!(scale_abs(1023, 1024), INPUT_ABS_MAX);
+        assert_eq!(scale_abs(2000, 1024), INPUT_ABS_MAX);
+        assert_eq!(scale_abs(512, 1025), INPUT_ABS_MAX / 2);
+    }
+
+    #[test]
+    fn abs_positions_on_empty_screens_are_zero() {
+        assert_eq!(scale_abs(0, 0), 0);
+        assert_eq!(scale_abs(5, 1), 0);
+    }
+}
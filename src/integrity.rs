@@ -0,0 +1,203 @@
+// flatkvm-qemu
+// Copyright (C) 2019  Sergio Lopez <slp@sinrega.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use crate::util::cache_dir;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TemplateManifest {
+    pub template_sha256: String,
+    pub kernel_sha256: String,
+}
+
+impl TemplateManifest {
+    pub fn load(path: &str) -> Result<TemplateManifest, String> {
+        let data = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+        serde_json::from_str(&data).map_err(|err| format!("{}: {}", path, err))
+    }
+}
+
+#[derive(Debug)]
+pub enum IntegrityError {
+    Io(String),
+    Mismatch {
+        path: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IntegrityError::Io(err) => write!(f, "integrity check failed: {}", err),
+            IntegrityError::Mismatch {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "integrity check failed: {} has digest {}, expected {}",
+                path, actual, expected
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DigestCacheEntry {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    ino: u64,
+    sha256: String,
+}
+
+// Digests of previously hashed files, so images weighing several GBs
+// don't need to be read on every launch. An entry is only trusted while
+// the file's size, mtime and inode are unchanged.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DigestCache {
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    entries: HashMap<String, DigestCacheEntry>,
+}
+
+impl DigestCache {
+    pub fn default_path() -> Option<PathBuf> {
+        Some(cache_dir()?.join("digests.json"))
+    }
+
+    pub fn load(path: PathBuf) -> DigestCache {
+        let mut cache: DigestCache = fs::read_to_string(&path)
+            .ok()
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .unwrap_or_default();
+        cache.path = Some(path);
+        cache
+    }
+
+    // Loads the cache from its default location, or returns an empty one
+    // that won't be persisted if there's none.
+    pub fn open_default() -> DigestCache {
+        match DigestCache::default_path() {
+            Some(path) => DigestCache::load(path),
+            None => DigestCache::default(),
+        }
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+        }
+
+        let data = serde_json::to_string(self).map_err(|err| err.to_string())?;
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, data).map_err(|err| err.to_string())?;
+        fs::rename(&tmp_path, path).map_err(|err| err.to_string())
+    }
+
+    pub fn file_sha256(&mut self, path: &str) -> Result<String, String> {
+        let metadata = fs::metadata(path).map_err(|err| format!("{}: {}", path, err))?;
+
+        if let Some(entry) = self.entries.get(path) {
+            if entry.size == metadata.size()
+                && entry.mtime == metadata.mtime()
+                && entry.mtime_nsec == metadata.mtime_nsec()
+                && entry.ino == metadata.ino()
+            {
+                return Ok(entry.sha256.to_string());
+            }
+        }
+
+        let sha256 = file_sha256(path)?;
+        self.entries.insert(
+            path.to_string(),
+            DigestCacheEntry {
+                size: metadata.size(),
+                mtime: metadata.mtime(),
+                mtime_nsec: metadata.mtime_nsec(),
+                ino: metadata.ino(),
+                sha256: sha256.to_string(),
+            },
+        );
+        Ok(sha256)
+    }
+
+    pub fn verify_file(&mut self, path: &str, expected: &str) -> Result<(), IntegrityError> {
+        let actual = self.file_sha256(path).map_err(IntegrityError::Io)?;
+
+        if actual.eq_ignore_ascii_case(expected) {
+            Ok(())
+        } else {
+            Err(IntegrityError::Mismatch {
+                path: path.to_string(),
+                expected: expected.to_string(),
+                actual,
+            })
+        }
+    }
+}
+
+pub fn data_sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+pub fn file_sha256(path: &str) -> Result<String, String> {
+    let mut file = File::open(path).map_err(|err| format!("{}: {}", path, err))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+
+    loop {
+        let len = file
+            .read(&mut buf)
+            .map_err(|err| format!("{}: {}", path, err))?;
+        if len == 0 {
+            break;
+        }
+        hasher.input(&buf[..len]);
+    }
+
+    Ok(format!("{:x}", hasher.result()))
+}
+
+// Checks the template and kernel against the digests in the manifest.
+pub fn verify_template(
+    manifest: &TemplateManifest,
+    template: &str,
+    kernel: &str,
+) -> Result<(), IntegrityError> {
+    let mut cache = DigestCache::open_default();
+
+    let result = cache
+        .verify_file(template, &manifest.template_sha256)
+        .and_then(|_| cache.verify_file(kernel, &manifest.kernel_sha256));
+    // Failing to update the cache only means rehashing on the next launch.
+    let _ = cache.save();
+
+    result
+}
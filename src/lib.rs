@@ -15,9 +15,17 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 pub mod agent;
+pub mod cgroup;
 pub mod clipboard;
 pub mod dbus_codegen;
 pub mod dbus_notifications;
-mod qmpconn;
+pub mod drive;
+pub mod image;
+pub mod input;
+pub mod integrity;
+pub mod memory;
+pub mod qmpconn;
 pub mod runner;
+pub mod template;
 mod util;
+pub mod vm;
@@ -0,0 +1,426 @@
+// flatkvm-qemu
+// Copyright (C) 2019  Sergio Lopez <slp@sinrega.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use crate::agent::AgentMemoryPressure;
+use crate::qmpconn::{BalloonStats, QmpConn};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs;
+use std::path::Path;
+
+// QOM path of the balloon device added by QemuRunner::balloon.
+pub const BALLOON_QOM_PATH: &str = "/machine/peripheral/balloon0";
+// QOM path of the virtio-mem device added by QemuRunner::maxmem_mb.
+pub const VIRTIO_MEM_QOM_PATH: &str = "/machine/peripheral/vmem0";
+
+const STAT_UNAVAILABLE: u64 = u64::MAX;
+
+// Decides the guest memory size from the stats reported by its balloon
+// driver: the guest is shrunk while a large share of its memory is
+// available, and grown back when it runs low. Sizes are in MiB.
+#[derive(Clone, Debug)]
+pub struct BalloonPolicy {
+    min_mb: u64,
+    max_mb: u64,
+    step_mb: u64,
+    shrink_above_pct: u64,
+    grow_below_pct: u64,
+    // last_update of the stats the latest decision was based on.
+    last_update: u64,
+}
+
+impl BalloonPolicy {
+    // "max_mb" should be the VM's ram_mb, as the balloon can't grow the
+    // guest beyond it.
+    pub fn new(min_mb: u64, max_mb: u64) -> BalloonPolicy {
+        BalloonPolicy {
+            min_mb,
+            max_mb,
+            step_mb: 256,
+            shrink_above_pct: 40,
+            grow_below_pct: 15,
+            last_update: 0,
+        }
+    }
+
+    pub fn step_mb(mut self, step_mb: u64) -> Self {
+        self.step_mb = step_mb;
+        self
+    }
+
+    // Percentage of available guest memory above which it's shrunk.
+    pub fn shrink_above_pct(mut self, pct: u64) -> Self {
+        self.shrink_above_pct = pct;
+        self
+    }
+
+    // Percentage of available guest memory below which it's grown.
+    pub fn grow_below_pct(mut self, pct: u64) -> Self {
+        self.grow_below_pct = pct;
+        self
+    }
+
+    // Returns the new size for a guest currently at "current_mb", or None
+    // if it should stay as it is.
+    pub fn target_mb(&self, current_mb: u64, stats: &BalloonStats) -> Option<u64> {
+        if stats.last_update == 0 {
+            return None;
+        }
+        let total = stats.stats.total_memory;
+        let available = if stats.stats.available_memory != STAT_UNAVAILABLE {
+            stats.stats.available_memory
+        } else {
+            stats.stats.free_memory
+        };
+        if total == STAT_UNAVAILABLE || total == 0 || available == STAT_UNAVAILABLE {
+            return None;
+        }
+
+        let available_pct = available.saturating_mul(100) / total;
+        let target = if available_pct > self.shrink_above_pct {
+            current_mb.saturating_sub(self.step_mb).max(self.min_mb)
+        } else if available_pct < self.grow_below_pct {
+            current_mb.saturating_add(self.step_mb).min(self.max_mb)
+        } else {
+            return None;
+        };
+
+        if target != current_mb {
+            Some(target)
+        } else {
+            None
+        }
+    }
+
+    // Resizes the guest according to its latest stats, returning the new
+    // size if it was changed. Meant to be called periodically, no more
+    // often than the stats are polled. Stats that haven't been refreshed
+    // since the previous call are ignored, so the guest isn't resized
+    // again before its reaction to the last change has been reported.
+    pub fn apply(&mut self, qmp: &QmpConn) -> Result<Option<u64>, String> {
+        let stats = qmp.query_balloon_stats(BALLOON_QOM_PATH)?;
+        if stats.last_update <= self.last_update {
+            return Ok(None);
+        }
+        self.last_update = stats.last_update;
+
+        let current_mb = qmp.query_balloon()?.actual >> 20;
+        match self.target_mb(current_mb, &stats) {
+            Some(target_mb) => {
+                qmp.balloon(target_mb << 20)?;
+                Ok(Some(target_mb))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+fn qom_get_mb(qmp: &QmpConn, path: &str, property: &str) -> Result<u64, String> {
+    match qmp.qom_get(path, property)?.as_u64() {
+        Some(bytes) => Ok(bytes >> 20),
+        None => Err(format!("{}: invalid {}", path, property)),
+    }
+}
+
+// Size of the memory the guest has actually plugged from the virtio-mem
+// device, which may lag behind the requested size.
+pub fn virtio_mem_size_mb(qmp: &QmpConn) -> Result<u64, String> {
+    qom_get_mb(qmp, VIRTIO_MEM_QOM_PATH, "size")
+}
+
+pub fn virtio_mem_requested_size_mb(qmp: &QmpConn) -> Result<u64, String> {
+    qom_get_mb(qmp, VIRTIO_MEM_QOM_PATH, "requested-size")
+}
+
+// Asks the guest to plug or unplug memory until the device provides
+// "size_mb", which must be a multiple of the device block size (2 MiB by
+// default on x86_64).
+pub fn set_virtio_mem_requested_size_mb(qmp: &QmpConn, size_mb: u64) -> Result<(), String> {
+    qmp.qom_set(VIRTIO_MEM_QOM_PATH, "requested-size", json!(size_mb << 20))
+}
+
+// Decides the size of the virtio-mem device from the memory pressure
+// reported by the agent: memory is added while the guest is stalling on
+// it or running low, and removed when a large share of it is available.
+// Sizes are in MiB and refer to the memory on top of ram_mb.
+#[derive(Clone, Debug)]
+pub struct VirtioMemPolicy {
+    max_mb: u64,
+    step_mb: u64,
+    grow_above_avg10: f32,
+    grow_below_pct: u64,
+    shrink_above_pct: u64,
+}
+
+impl VirtioMemPolicy {
+    // "max_mb" should be the VM's maxmem_mb minus its ram_mb.
+    pub fn new(max_mb: u64) -> VirtioMemPolicy {
+        VirtioMemPolicy {
+            max_mb,
+            step_mb: 128,
+            grow_above_avg10: 10.0,
+            grow_below_pct: 15,
+            shrink_above_pct: 40,
+        }
+    }
+
+    // Must be a multiple of the device block size.
+    pub fn step_mb(mut self, step_mb: u64) -> Self {
+        self.step_mb = step_mb;
+        self
+    }
+
+    // PSI "some" average above which the guest is grown.
+    pub fn grow_above_avg10(mut self, avg10: f32) -> Self {
+        self.grow_above_avg10 = avg10;
+        self
+    }
+
+    // Percentage of available guest memory below which it's grown.
+    pub fn grow_below_pct(mut self, pct: u64) -> Self {
+        self.grow_below_pct = pct;
+        self
+    }
+
+    // Percentage of available guest memory above which it's shrunk.
+    pub fn shrink_above_pct(mut self, pct: u64) -> Self {
+        self.shrink_above_pct = pct;
+        self
+    }
+
+    // Returns the new requested size for a device currently at
+    // "current_mb", or None if it should stay as it is.
+    pub fn target_mb(&self, current_mb: u64, pressure: &AgentMemoryPressure) -> Option<u64> {
+        if pressure.total_kb == 0 {
+            return None;
+        }
+
+        let available_pct = pressure.available_kb.saturating_mul(100) / pressure.total_kb;
+        let target =
+            if pressure.some_avg10 > self.grow_above_avg10 || available_pct < self.grow_below_pct {
+                current_mb.saturating_add(self.step_mb).min(self.max_mb)
+            } else if available_pct > self.shrink_above_pct {
+                current_mb.saturating_sub(self.step_mb)
+            } else {
+                return None;
+            };
+
+        if target != current_mb {
+            Some(target)
+        } else {
+            None
+        }
+    }
+
+    // Resizes the device according to a report from the agent, returning
+    // the new requested size if it was changed.
+    pub fn apply(
+        &self,
+        qmp: &QmpConn,
+        pressure: &AgentMemoryPressure,
+    ) -> Result<Option<u64>, String> {
+        let current_mb = virtio_mem_requested_size_mb(qmp)?;
+
+        match self.target_mb(current_mb, pressure) {
+            Some(target_mb) => {
+                set_virtio_mem_requested_size_mb(qmp, target_mb)?;
+                Ok(Some(target_mb))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+const KSM_SYSFS_DIR: &str = "/sys/kernel/mm/ksm";
+
+fn page_size() -> u64 {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
+}
+
+fn read_ksm_value(name: &str) -> Result<u64, String> {
+    let path = Path::new(KSM_SYSFS_DIR).join(name);
+    fs::read_to_string(&path)
+        .map_err(|err| format!("{}: {}", path.display(), err))?
+        .trim()
+        .parse()
+        .map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+// System-wide KSM counters, in pages.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KsmStats {
+    pub run: u64,
+    pub pages_shared: u64,
+    pub pages_sharing: u64,
+    pub pages_unshared: u64,
+    pub full_scans: u64,
+}
+
+impl KsmStats {
+    pub fn read() -> Result<KsmStats, String> {
+        Ok(KsmStats {
+            run: read_ksm_value("run")?,
+            pages_shared: read_ksm_value("pages_shared")?,
+            pages_sharing: read_ksm_value("pages_sharing")?,
+            pages_unshared: read_ksm_value("pages_unshared")?,
+            full_scans: read_ksm_value("full_scans")?,
+        })
+    }
+
+    // Whether ksmd is merging pages, which must be enabled by root by
+    // writing 1 to /sys/kernel/mm/ksm/run.
+    pub fn is_running(&self) -> bool {
+        self.run == 1
+    }
+
+    // Memory saved across the whole system.
+    pub fn saved_bytes(&self) -> u64 {
+        self.pages_sharing * page_size()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InstanceKsmStats {
+    pub name: String,
+    pub pid: u32,
+    pub merging_pages: u64,
+    // Size of the instance's pages backed by a KSM page. This isn't how
+    // much it saves, as each shared page still takes up one page.
+    pub merged_bytes: u64,
+}
+
+impl InstanceKsmStats {
+    // Reads the pages of the process "pid" merged by KSM, which requires
+    // Linux 6.1 or later.
+    pub fn read(name: &str, pid: u32) -> Result<InstanceKsmStats, String> {
+        let path = format!("/proc/{}/ksm_merging_pages", pid);
+        let merging_pages: u64 = fs::read_to_string(&path)
+            .map_err(|err| format!("{}: {}", path, err))?
+            .trim()
+            .parse()
+            .map_err(|err| format!("{}: {}", path, err))?;
+
+        Ok(InstanceKsmStats {
+            name: name.to_string(),
+            pid,
+            merging_pages,
+            merged_bytes: merging_pages * page_size(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KsmReport {
+    pub system: KsmStats,
+    pub instances: Vec<InstanceKsmStats>,
+}
+
+// Reports the KSM stats of the running flatkvm instances, given as
+// their names and QEMU pids (see QemuVm::get_pid). Instances whose stats
+// can't be read, because they've exited or the kernel is too old, are
+// left out.
+pub fn ksm_report(instances: &[(&str, u32)]) -> Result<KsmReport, String> {
+    let system = KsmStats::read()?;
+    let instances = instances
+        .iter()
+        .filter_map(|(name, pid)| InstanceKsmStats::read(name, *pid).ok())
+        .collect();
+
+    Ok(KsmReport { system, instances })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qmpconn::GuestMemoryStats;
+
+    const MB: u64 = 1024 * 1024;
+
+    fn stats(available_mb: u64, total_mb: u64) -> BalloonStats {
+        BalloonStats {
+            stats: GuestMemoryStats {
+                free_memory: STAT_UNAVAILABLE,
+                available_memory: available_mb * MB,
+                total_memory: total_mb * MB,
+                disk_caches: STAT_UNAVAILABLE,
+            },
+            last_update: 1,
+        }
+    }
+
+    #[test]
+    fn shrinks_while_mostly_available() {
+        let policy = BalloonPolicy::new(512, 4096);
+        assert_eq!(policy.target_mb(2048, &stats(1024, 2048)), Some(1792));
+        // Never below min_mb.
+        assert_eq!(policy.target_mb(600, &stats(500, 600)), Some(512));
+        assert_eq!(policy.target_mb(512, &stats(500, 512)), None);
+    }
+
+    #[test]
+    fn grows_when_running_low() {
+        let policy = BalloonPolicy::new(512, 4096);
+        assert_eq!(policy.target_mb(2048, &stats(100, 2048)), Some(2304));
+        // Never above max_mb.
+        assert_eq!(policy.target_mb(4000, &stats(100, 4000)), Some(4096));
+        assert_eq!(policy.target_mb(4096, &stats(100, 4096)), None);
+    }
+
+    #[test]
+    fn stays_between_thresholds() {
+        let policy = BalloonPolicy::new(512, 4096);
+        // 40% and 15% are both within the range.
+        assert_eq!(policy.target_mb(1000, &stats(400, 1000)), None);
+        assert_eq!(policy.target_mb(1000, &stats(150, 1000)), None);
+        assert_eq!(policy.target_mb(1000, &stats(250, 1000)), None);
+    }
+
+    #[test]
+    fn thresholds_and_step_are_configurable() {
+        let policy = BalloonPolicy::new(512, 4096)
+            .step_mb(128)
+            .shrink_above_pct(60)
+            .grow_below_pct(30);
+        assert_eq!(policy.target_mb(1000, &stats(500, 1000)), None);
+        assert_eq!(policy.target_mb(1000, &stats(700, 1000)), Some(872));
+        assert_eq!(policy.target_mb(1000, &stats(200, 1000)), Some(1128));
+    }
+
+    #[test]
+    fn falls_back_to_free_memory() {
+        let policy = BalloonPolicy::new(512, 4096);
+        let mut stats = stats(0, 2048);
+        stats.stats.available_memory = STAT_UNAVAILABLE;
+        stats.stats.free_memory = 1024 * MB;
+        assert_eq!(policy.target_mb(2048, &stats), Some(1792));
+    }
+
+    #[test]
+    fn ignores_missing_stats() {
+        let policy = BalloonPolicy::new(512, 4096);
+        let mut no_update = stats(1024, 2048);
+        no_update.last_update = 0;
+        assert_eq!(policy.target_mb(2048, &no_update), None);
+
+        let mut no_total = stats(1024, 2048);
+        no_total.stats.total_memory = STAT_UNAVAILABLE;
+        assert_eq!(policy.target_mb(2048, &no_total), None);
+
+        let mut nothing_available = stats(1024, 2048);
+        nothing_available.stats.available_memory = STAT_UNAVAILABLE;
+        assert_eq!(policy.target_mb(2048, &nothing_available), None);
+    }
+}
@@ -14,32 +14,726 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use crate::util::open_socket;
-use qapi::{qmp, Qmp};
+use crate::input::InputEvent;
+use crate::util::{open_socket, runtime_dir, send_with_fd};
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
+#[derive(Clone, Debug)]
+pub enum QmpEvent {
+    Shutdown {
+        guest: bool,
+        reason: String,
+    },
+    Reset {
+        guest: bool,
+        reason: String,
+    },
+    Stop,
+    Resume,
+    GuestPanicked {
+        action: String,
+        info: Option<Value>,
+    },
+    BlockIoError {
+        device: String,
+        node_name: Option<String>,
+        operation: String,
+        action: String,
+        nospace: bool,
+        reason: String,
+    },
+    DeviceDeleted {
+        device: Option<String>,
+        path: String,
+    },
+    Watchdog {
+        action: String,
+    },
+    Other {
+        name: String,
+        data: Value,
+    },
+}
+
+#[derive(Deserialize)]
+struct ShutdownData {
+    guest: bool,
+    #[serde(default)]
+    reason: String,
+}
+
+#[derive(Deserialize)]
+struct GuestPanickedData {
+    action: String,
+    info: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct BlockIoErrorData {
+    device: String,
+    #[serde(rename = "node-name")]
+    node_name: Option<String>,
+    operation: String,
+    action: String,
+    #[serde(default)]
+    nospace: bool,
+    #[serde(default)]
+    reason: String,
+}
+
+#[derive(Deserialize)]
+struct WatchdogData {
+    action: String,
+}
+
+#[derive(Deserialize)]
+struct DeviceDeletedData {
+    device: Option<String>,
+    path: String,
+}
+
+impl QmpEvent {
+    fn parse(name: &str, data: Value) -> QmpEvent {
+        let event = match name {
+            "SHUTDOWN" => serde_json::from_value(data.clone())
+                .map(|d: ShutdownData| QmpEvent::Shutdown {
+                    guest: d.guest,
+                    reason: d.reason,
+                })
+                .ok(),
+            "RESET" => serde_json::from_value(data.clone())
+                .map(|d: ShutdownData| QmpEvent::Reset {
+                    guest: d.guest,
+                    reason: d.reason,
+                })
+                .ok(),
+            "STOP" => Some(QmpEvent::Stop),
+            "RESUME" => Some(QmpEvent::Resume),
+            "GUEST_PANICKED" => serde_json::from_value(data.clone())
+                .map(|d: GuestPanickedData| QmpEvent::GuestPanicked {
+                    action: d.action,
+                    info: d.info,
+                })
+                .ok(),
+            "BLOCK_IO_ERROR" => serde_json::from_value(data.clone())
+                .map(|d: BlockIoErrorData| QmpEvent::BlockIoError {
+                    device: d.device,
+                    node_name: d.node_name,
+                    operation: d.operation,
+                    action: d.action,
+                    nospace: d.nospace,
+                    reason: d.reason,
+                })
+                .ok(),
+            "DEVICE_DELETED" => serde_json::from_value(data.clone())
+                .map(|d: DeviceDeletedData| QmpEvent::DeviceDeleted {
+                    device: d.device,
+                    path: d.path,
+                })
+                .ok(),
+            "WATCHDOG" => serde_json::from_value(data.clone())
+                .map(|d: WatchdogData| QmpEvent::Watchdog { action: d.action })
+                .ok(),
+            _ => None,
+        };
+
+        match event {
+            Some(event) => event,
+            None => QmpEvent::Other {
+                name: name.to_string(),
+                data,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatusInfo {
+    pub running: bool,
+    pub status: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VersionTriple {
+    pub major: u32,
+    pub minor: u32,
+    pub micro: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VersionInfo {
+    pub qemu: VersionTriple,
+    pub package: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KvmInfo {
+    pub enabled: bool,
+    pub present: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageInfo {
+    pub filename: String,
+    pub format: String,
+    #[serde(rename = "virtual-size")]
+    pub virtual_size: u64,
+    #[serde(rename = "actual-size")]
+    pub actual_size: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockDeviceInfo {
+    pub file: String,
+    #[serde(rename = "node-name")]
+    pub node_name: Option<String>,
+    pub ro: bool,
+    pub drv: String,
+    pub encrypted: bool,
+    pub image: ImageInfo,
+    #[serde(flatten)]
+    pub throttle: IoThrottle,
+}
+
+// I/O limits of a block device, in bytes and operations per second. Zero
+// means unlimited. The "_max" values allow bursts above the base limits.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct IoThrottle {
+    #[serde(default)]
+    pub bps: u64,
+    #[serde(default)]
+    pub bps_rd: u64,
+    #[serde(default)]
+    pub bps_wr: u64,
+    #[serde(default)]
+    pub iops: u64,
+    #[serde(default)]
+    pub iops_rd: u64,
+    #[serde(default)]
+    pub iops_wr: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bps_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iops_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iops_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+impl IoThrottle {
+    pub fn is_unlimited(&self) -> bool {
+        self.bps == 0
+            && self.bps_rd == 0
+            && self.bps_wr == 0
+            && self.iops == 0
+            && self.iops_rd == 0
+            && self.iops_wr == 0
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockInfo {
+    pub device: String,
+    pub qdev: Option<String>,
+    pub removable: bool,
+    pub locked: bool,
+    pub inserted: Option<BlockDeviceInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockDeviceStats {
+    pub rd_bytes: u64,
+    pub wr_bytes: u64,
+    pub rd_operations: u64,
+    pub wr_operations: u64,
+    pub flush_operations: u64,
+    pub rd_total_time_ns: u64,
+    pub wr_total_time_ns: u64,
+    pub flush_total_time_ns: u64,
+    pub wr_highest_offset: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockStats {
+    pub device: Option<String>,
+    pub qdev: Option<String>,
+    #[serde(rename = "node-name")]
+    pub node_name: Option<String>,
+    pub stats: BlockDeviceStats,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CpuInfoFast {
+    #[serde(rename = "cpu-index")]
+    pub cpu_index: u32,
+    #[serde(rename = "qom-path")]
+    pub qom_path: String,
+    #[serde(rename = "thread-id")]
+    pub thread_id: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MigrationInfo {
+    pub status: Option<String>,
+    #[serde(rename = "error-desc")]
+    pub error_desc: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BalloonInfo {
+    pub actual: u64,
+}
+
+// Memory statistics reported by the guest balloon driver, in bytes. The
+// ones the guest doesn't provide are set to u64::MAX.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GuestMemoryStats {
+    #[serde(rename = "stat-free-memory")]
+    pub free_memory: u64,
+    #[serde(rename = "stat-available-memory")]
+    pub available_memory: u64,
+    #[serde(rename = "stat-total-memory")]
+    pub total_memory: u64,
+    #[serde(rename = "stat-disk-caches")]
+    pub disk_caches: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BalloonStats {
+    pub stats: GuestMemoryStats,
+    // Guest time of the last update, zero if none has been received yet.
+    #[serde(rename = "last-update")]
+    pub last_update: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScreendumpFormat {
+    Ppm,
+    Png,
+}
+
+impl ScreendumpFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScreendumpFormat::Ppm => "ppm",
+            ScreendumpFormat::Png => "png",
+        }
+    }
+}
+
+static SCREENDUMP_ID: AtomicUsize = AtomicUsize::new(0);
+
+// Errors returned by execute_timeout when QEMU closed the connection
+// before replying, as it does on quit, and when it didn't reply in time.
+pub const QMP_CLOSED: &str = "QMP connection closed";
+pub const QMP_TIMEOUT: &str = "QMP command timed out";
+
+// A reply to a command, along with the id the command was sent with.
+type Reply = (Option<u64>, Result<Value, String>);
+
+// None once the connection is closed.
+type Subscribers = Arc<Mutex<Option<Vec<Sender<QmpEvent>>>>>;
+
+// A QMP connection whose socket is read by a dedicated thread, which
+// hands command replies back to execute() and broadcasts asynchronous
+// events to every subscriber. This allows commands to be issued from any
+// thread while events are being consumed.
 pub struct QmpConn {
-    stream: UnixStream,
+    stream: Mutex<UnixStream>,
+    replies: Mutex<Receiver<Reply>>,
+    subscribers: Subscribers,
+    next_id: AtomicU64,
+}
+
+fn reader_thread(stream: UnixStream, replies: Sender<Reply>, subscribers: Subscribers) {
+    let reader = BufReader::new(stream);
+
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let msg: Value = match serde_json::from_str(&line) {
+            Ok(msg) => msg,
+            Err(_) => continue,
+        };
+
+        if let Some(name) = msg["event"].as_str() {
+            let event = QmpEvent::parse(name, msg["data"].clone());
+            if let Some(subscribers) = subscribers.lock().unwrap().as_mut() {
+                subscribers.retain(|s| s.send(event.clone()).is_ok());
+            }
+        } else if let Some(ret) = msg.get("return") {
+            if replies.send((msg["id"].as_u64(), Ok(ret.clone()))).is_err() {
+                break;
+            }
+        } else if let Some(err) = msg.get("error") {
+            let desc = err["desc"].as_str().unwrap_or("unknown error");
+            if replies
+                .send((msg["id"].as_u64(), Err(desc.to_string())))
+                .is_err()
+            {
+                break;
+            }
+        }
+    }
+
+    // Drops the senders, so the subscribers see the connection closed.
+    subscribers.lock().unwrap().take();
 }
 
 impl QmpConn {
     pub fn new(sockpath: String) -> Result<QmpConn, String> {
         let stream = open_socket(sockpath).map_err(|err| err.to_string())?;
+        let reader = stream.try_clone().map_err(|err| err.to_string())?;
+        let (sender, replies) = channel();
+        let subscribers: Subscribers = Arc::new(Mutex::new(Some(Vec::new())));
 
-        Ok(QmpConn { stream })
+        let thread_subscribers = subscribers.clone();
+        thread::spawn(move || reader_thread(reader, sender, thread_subscribers));
+
+        Ok(QmpConn {
+            stream: Mutex::new(stream),
+            replies: Mutex::new(replies),
+            subscribers,
+            next_id: AtomicU64::new(0),
+        })
     }
 
     pub fn initialize(&self) -> Result<(), std::io::Error> {
-        let mut qmp = Qmp::from_stream(&self.stream);
-        qmp.handshake()?;
+        self.execute("qmp_capabilities", None)
+            .map_err(std::io::Error::other)?;
         Ok(())
     }
 
-    pub fn send_shutdown(&self) -> Result<(), String> {
-        let mut qmp = Qmp::from_stream(&self.stream);
-        qmp.execute(&qmp::system_powerdown {})
-            .unwrap()
+    // Returns a channel receiving every event emitted from now on, which
+    // is disconnected once the connection is closed.
+    pub fn subscribe(&self) -> Receiver<QmpEvent> {
+        let (sender, receiver) = channel();
+        if let Some(subscribers) = self.subscribers.lock().unwrap().as_mut() {
+            subscribers.push(sender);
+        }
+        receiver
+    }
+
+    // Calls "callback" from a dedicated thread for every event emitted
+    // from now on, until the connection is closed.
+    pub fn on_event<F>(&self, callback: F)
+    where
+        F: Fn(QmpEvent) + Send + 'static,
+    {
+        let receiver = self.subscribe();
+        thread::spawn(move || {
+            for event in receiver.iter() {
+                callback(event);
+            }
+        });
+    }
+
+    pub fn execute(&self, command: &str, arguments: Option<Value>) -> Result<Value, String> {
+        self.execute_with_fd(command, arguments, None, None)
+    }
+
+    // Like execute, but gives up with QMP_TIMEOUT if QEMU doesn't reply
+    // within "timeout", so a hung QEMU can't block the caller.
+    pub fn execute_timeout(
+        &self,
+        command: &str,
+        arguments: Option<Value>,
+        timeout: Duration,
+    ) -> Result<Value, String> {
+        self.execute_with_fd(command, arguments, None, Some(timeout))
+    }
+
+    fn execute_with_fd(
+        &self,
+        command: &str,
+        arguments: Option<Value>,
+        fd: Option<RawFd>,
+        timeout: Option<Duration>,
+    ) -> Result<Value, String> {
+        // Replies carry the id of their command, so a late reply to a
+        // command that timed out isn't taken for the reply to this one.
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut cmd = json!({ "execute": command, "id": id });
+        if let Some(arguments) = arguments {
+            cmd["arguments"] = arguments;
+        }
+        let mut msg = serde_json::to_string(&cmd).map_err(|err| err.to_string())?;
+        msg.push('\n');
+
+        // Holding the receiver for the whole exchange keeps concurrent
+        // callers from picking up each other's replies.
+        let replies = self.replies.lock().unwrap();
+        {
+            let mut stream = self.stream.lock().unwrap();
+            match fd {
+                Some(fd) => send_with_fd(&stream, msg.as_bytes(), fd),
+                None => stream.write_all(msg.as_bytes()),
+            }
             .map_err(|err| err.to_string())?;
+        }
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let reply = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    match replies.recv_timeout(remaining) {
+                        Ok(reply) => reply,
+                        Err(RecvTimeoutError::Timeout) => return Err(QMP_TIMEOUT.to_string()),
+                        Err(RecvTimeoutError::Disconnected) => return Err(QMP_CLOSED.to_string()),
+                    }
+                }
+                None => match replies.recv() {
+                    Ok(reply) => reply,
+                    Err(_) => return Err(QMP_CLOSED.to_string()),
+                },
+            };
+
+            match reply {
+                (Some(reply_id), reply) if reply_id == id => {
+                    return reply.map_err(|err| format!("{}: {}", command, err))
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    fn query<T: DeserializeOwned>(&self, command: &str) -> Result<T, String> {
+        let ret = self.execute(command, None)?;
+        serde_json::from_value(ret).map_err(|err| format!("{}: {}", command, err))
+    }
+
+    pub fn send_shutdown(&self) -> Result<(), String> {
+        self.execute("system_powerdown", None)?;
+        Ok(())
+    }
+
+    // Hands "fd" over to QEMU, which can then refer to it as "name", as
+    // in the "fd:name" migration URI.
+    pub fn getfd(&self, name: &str, fd: RawFd) -> Result<(), String> {
+        self.execute_with_fd("getfd", Some(json!({ "fdname": name })), Some(fd), None)?;
+        Ok(())
+    }
+
+    // Closes an fd passed with getfd that QEMU hasn't consumed.
+    pub fn closefd(&self, name: &str) -> Result<(), String> {
+        self.execute("closefd", Some(json!({ "fdname": name })))?;
+        Ok(())
+    }
+
+    // Adds "fd" to a new fd set, returning its id. QEMU can then open the
+    // file as "/dev/fdset/<id>", with the same access mode as "fd".
+    pub fn add_fd(&self, fd: RawFd) -> Result<u64, String> {
+        let ret = self.execute_with_fd("add-fd", None, Some(fd), None)?;
+        ret["fdset-id"]
+            .as_u64()
+            .ok_or_else(|| "add-fd: no fdset-id".to_string())
+    }
+
+    pub fn remove_fd(&self, fdset_id: u64) -> Result<(), String> {
+        self.execute("remove-fd", Some(json!({ "fdset-id": fdset_id })))?;
+        Ok(())
+    }
+
+    pub fn migrate(&self, uri: &str) -> Result<(), String> {
+        self.execute("migrate", Some(json!({ "uri": uri })))?;
         Ok(())
     }
+
+    pub fn query_migrate(&self) -> Result<MigrationInfo, String> {
+        self.query("query-migrate")
+    }
+
+    // Writes an ELF core of the guest memory to "protocol", such as
+    // "fd:name" after a getfd, returning once the dump is complete.
+    pub fn dump_guest_memory(&self, protocol: &str) -> Result<(), String> {
+        self.execute(
+            "dump-guest-memory",
+            Some(json!({ "paging": false, "protocol": protocol })),
+        )?;
+        Ok(())
+    }
+
+    pub fn device_add(&self, props: Value) -> Result<(), String> {
+        self.execute("device_add", Some(props))?;
+        Ok(())
+    }
+
+    // Only requests the removal, which is complete once the guest has
+    // released the device and DEVICE_DELETED is emitted.
+    pub fn device_del(&self, id: &str) -> Result<(), String> {
+        self.execute("device_del", Some(json!({ "id": id })))?;
+        Ok(())
+    }
+
+    pub fn stop(&self) -> Result<(), String> {
+        self.execute("stop", None)?;
+        Ok(())
+    }
+
+    pub fn cont(&self) -> Result<(), String> {
+        self.execute("cont", None)?;
+        Ok(())
+    }
+
+    pub fn query_status(&self) -> Result<StatusInfo, String> {
+        self.query("query-status")
+    }
+
+    pub fn query_version(&self) -> Result<VersionInfo, String> {
+        self.query("query-version")
+    }
+
+    pub fn query_kvm(&self) -> Result<KvmInfo, String> {
+        self.query("query-kvm")
+    }
+
+    pub fn query_block(&self) -> Result<Vec<BlockInfo>, String> {
+        self.query("query-block")
+    }
+
+    pub fn query_blockstats(&self) -> Result<Vec<BlockStats>, String> {
+        self.query("query-blockstats")
+    }
+
+    pub fn query_cpus_fast(&self) -> Result<Vec<CpuInfoFast>, String> {
+        self.query("query-cpus-fast")
+    }
+
+    // Fails if the VM has no balloon device.
+    pub fn query_balloon(&self) -> Result<BalloonInfo, String> {
+        self.query("query-balloon")
+    }
+
+    // Applies "throttle" to the block device whose qdev id is "id", such as
+    // "datadisk". An all-zero IoThrottle lifts the limits.
+    pub fn block_set_io_throttle(&self, id: &str, throttle: &IoThrottle) -> Result<(), String> {
+        let mut args = serde_json::to_value(throttle).map_err(|err| err.to_string())?;
+        args["id"] = json!(id);
+        self.execute("block_set_io_throttle", Some(args))?;
+        Ok(())
+    }
+
+    pub fn query_io_throttle(&self, id: &str) -> Result<IoThrottle, String> {
+        let prefix = format!("/machine/peripheral/{}/", id);
+        for block in self.query_block()? {
+            let matches = match &block.qdev {
+                Some(qdev) => qdev == id || qdev.starts_with(&prefix),
+                None => false,
+            };
+            if matches {
+                return match block.inserted {
+                    Some(inserted) => Ok(inserted.throttle),
+                    None => Err(format!("no medium in {}", id)),
+                };
+            }
+        }
+        Err(format!("unknown block device {}", id))
+    }
+
+    // Sets the guest memory size to "target" bytes, by inflating or
+    // deflating the balloon.
+    pub fn balloon(&self, target: u64) -> Result<(), String> {
+        self.execute("balloon", Some(json!({ "value": target })))?;
+        Ok(())
+    }
+
+    pub fn qom_get(&self, path: &str, property: &str) -> Result<Value, String> {
+        self.execute(
+            "qom-get",
+            Some(json!({ "path": path, "property": property })),
+        )
+    }
+
+    pub fn qom_set(&self, path: &str, property: &str, value: Value) -> Result<(), String> {
+        self.execute(
+            "qom-set",
+            Some(json!({ "path": path, "property": property, "value": value })),
+        )?;
+        Ok(())
+    }
+
+    // Only returns fresh stats once polling has been enabled on the device
+    // at "path", see QemuRunner::balloon_stats_interval.
+    pub fn query_balloon_stats(&self, path: &str) -> Result<BalloonStats, String> {
+        let ret = self.qom_get(path, "guest-stats")?;
+        serde_json::from_value(ret).map_err(|err| format!("guest-stats: {}", err))
+    }
+
+    // Presses "keys" (qcodes) together and releases them after "hold_time_ms",
+    // or QEMU's default of 100ms.
+    pub fn send_key(&self, keys: &[&str], hold_time_ms: Option<u32>) -> Result<(), String> {
+        let keys: Vec<Value> = keys
+            .iter()
+            .map(|key| json!({ "type": "qcode", "data": key }))
+            .collect();
+        let mut args = json!({ "keys": keys });
+        if let Some(hold_time) = hold_time_ms {
+            args["hold-time"] = json!(hold_time);
+        }
+        self.execute("send-key", Some(args))?;
+        Ok(())
+    }
+
+    pub fn input_send_event(&self, events: &[InputEvent]) -> Result<(), String> {
+        let events: Vec<Value> = events.iter().map(InputEvent::to_json).collect();
+        self.execute("input-send-event", Some(json!({ "events": events })))?;
+        Ok(())
+    }
+
+    // Saves the guest console to "path", which is written by QEMU itself.
+    // PPM is the only format supported by QEMU before 7.1.
+    pub fn screendump(&self, path: &str, format: ScreendumpFormat) -> Result<(), String> {
+        let mut args = json!({ "filename": path });
+        if format != ScreendumpFormat::Ppm {
+            args["format"] = json!(format.as_str());
+        }
+        self.execute("screendump", Some(args))?;
+        Ok(())
+    }
+
+    // QEMU writes the image through an fd passed over QMP, so this works
+    // even if it can't see our files, as when it runs under bubblewrap.
+    pub fn screendump_bytes(&self, format: ScreendumpFormat) -> Result<Vec<u8>, String> {
+        let path = runtime_dir().join(format!(
+            "flatkvm-screendump-{}-{}.{}",
+            std::process::id(),
+            SCREENDUMP_ID.fetch_add(1, Ordering::SeqCst),
+            format.as_str()
+        ));
+        let output = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        let input = File::open(&path).map_err(|err| format!("{}: {}", path.display(), err));
+        let _ = fs::remove_file(&path);
+        let mut input = input?;
+
+        let fdset_id = self.add_fd(output.as_raw_fd())?;
+        let result = self.screendump(&format!("/dev/fdset/{}", fdset_id), format);
+        let _ = self.remove_fd(fdset_id);
+        result?;
+
+        let mut data = Vec::new();
+        input
+            .read_to_end(&mut data)
+            .map_err(|err| err.to_string())?;
+        Ok(data)
+    }
 }
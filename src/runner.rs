@@ -14,14 +14,48 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use crate::agent::AgentHost;
+use crate::agent::{AgentHost, AGENT_VERSION};
+use crate::cgroup::{Cgroup, CgroupLimits};
+use crate::drive::{
+    QemuDrive, QemuDriveBus, QemuDriveFormat, QemuDriveMount, QemuDriveRole, QemuDriveSecret,
+    QemuExtraDrive,
+};
+use crate::image::{create_image, create_overlay};
+use crate::integrity::{
+    data_sha256, file_sha256, verify_template, DigestCache, IntegrityError, TemplateManifest,
+};
+use crate::memory::BALLOON_QOM_PATH;
 use crate::qmpconn::QmpConn;
+use crate::template::{find_template, TemplateInfo};
+use crate::util::{cache_dir, runtime_dir};
+use crate::vm::{
+    pin_emulator_threads, pin_vcpu_threads, QemuVm, SavedStateInfo, STATE_FILE, STATE_INFO_FILE,
+    STATE_TEMPLATE_OVERLAY_FILE,
+};
 use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
 use shlex::split;
 use std::env;
-use std::process::{Child, Command, Stdio};
+use std::fmt;
+use std::fs::{self, DirBuilder, File};
+use std::io::ErrorKind;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, Stdio};
+use std::time::Duration;
 
-#[derive(Debug, Serialize, Deserialize)]
+// Used when no versioned template is installed, as before templates were
+// discovered.
+const LEGACY_TEMPLATE: &str = "/usr/share/flatkvm/template.qcow2";
+const LEGACY_KERNEL: &str = "/usr/share/flatkvm/vmlinuz.flatkvm";
+
+// How long saving or loading a warm VM state may take.
+const WARM_STATE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum QemuSharedDirType {
     FlatpakSystemDir,
     FlatpakUserDir,
@@ -37,47 +71,179 @@ pub enum QemuSharedDirSecurity {
     None,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum QemuSharedDirTransport {
+    #[default]
+    Virtio9p,
+    Virtiofs,
+}
+
+// Lets callers of QemuRunner::run tell a template that failed its integrity
+// check apart from any other launch failure.
+#[derive(Debug)]
+pub enum LaunchError {
+    Integrity(IntegrityError),
+    Other(String),
+}
+
+impl fmt::Display for LaunchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LaunchError::Integrity(err) => err.fmt(f),
+            LaunchError::Other(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<String> for LaunchError {
+    fn from(err: String) -> LaunchError {
+        LaunchError::Other(err)
+    }
+}
+
+impl From<LaunchError> for String {
+    fn from(err: LaunchError) -> String {
+        err.to_string()
+    }
+}
+
+// What QEMU does when the guest stops feeding the watchdog.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum QemuWatchdogAction {
+    Reset,
+    Shutdown,
+    Poweroff,
+    Pause,
+    Debug,
+    None,
+    InjectNmi,
+}
+
+impl QemuWatchdogAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QemuWatchdogAction::Reset => "reset",
+            QemuWatchdogAction::Shutdown => "shutdown",
+            QemuWatchdogAction::Poweroff => "poweroff",
+            QemuWatchdogAction::Pause => "pause",
+            QemuWatchdogAction::Debug => "debug",
+            QemuWatchdogAction::None => "none",
+            QemuWatchdogAction::InjectNmi => "inject-nmi",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct QemuSharedDir {
     pub dir_type: QemuSharedDirType,
     pub app_name: String,
     pub source: String,
     pub tag: String,
     pub readonly: bool,
+    #[serde(default)]
+    pub transport: QemuSharedDirTransport,
 }
 
 pub struct QemuRunner {
     name: String,
     vcpu_num: u32,
+    smp_topology: Option<(u32, u32, u32)>,
+    vcpu_affinity: Vec<usize>,
+    emulator_affinity: Vec<usize>,
     ram_mb: u32,
-    template: String,
-    data_disk: String,
+    maxmem_mb: Option<u32>,
+    mem_merge: Option<bool>,
+    template: QemuDrive,
+    data_disk: QemuDrive,
+    extra_drives: Vec<QemuExtraDrive>,
+    overlay_dir: String,
     kernel: String,
+    manifest: Option<String>,
     agent_sock_path: Option<String>,
     qmp_sock_path: Option<String>,
     volatile: bool,
     network: bool,
     audio: bool,
     virgl: bool,
+    headless: bool,
+    iothreads: bool,
+    blk_multiqueue: bool,
     shared_dirs: Vec<QemuSharedDir>,
+    warm_boot: bool,
+    shared_dir_hotplug: bool,
+    usb: bool,
+    tablet: bool,
+    balloon: bool,
+    balloon_stats_interval: u32,
+    cgroup_limits: Option<CgroupLimits>,
+    sandbox: bool,
+    bubblewrap: Option<String>,
+    sandbox_binds: Vec<(String, bool)>,
+    pvpanic: bool,
+    watchdog: Option<QemuWatchdogAction>,
+    qemu_log: Option<String>,
+    serial_log: Option<String>,
+    crash_dir: Option<String>,
+    virtiofsd: String,
+}
+
+fn bwrap_bind(args: &mut Vec<String>, path: &str, readonly: bool) {
+    let flag = if readonly { "--ro-bind" } else { "--bind" };
+    args.extend(vec![flag.to_string(), path.to_string(), path.to_string()]);
 }
 
 impl QemuRunner {
+    // Uses the newest installed template compatible with this host's agent
+    // protocol, which can be overridden with template_info or template and
+    // kernel. Without any, falls back to the unversioned template and
+    // kernel installed in /usr/share/flatkvm.
     pub fn new(name: String, data_disk: String) -> QemuRunner {
+        let (template, kernel, manifest) = match find_template(AGENT_VERSION) {
+            Some(info) => (info.template, info.kernel, info.manifest),
+            None => (LEGACY_TEMPLATE.to_string(), LEGACY_KERNEL.to_string(), None),
+        };
+
         QemuRunner {
-            name: name,
+            name,
             vcpu_num: 1,
+            smp_topology: None,
+            vcpu_affinity: Vec::new(),
+            emulator_affinity: Vec::new(),
             ram_mb: 1024,
-            template: "/usr/share/flatkvm/template.qcow2".to_string(),
-            data_disk: data_disk,
-            kernel: "/usr/share/flatkvm/vmlinuz.flatkvm".to_string(),
+            maxmem_mb: None,
+            mem_merge: None,
+            template: QemuDrive::new(template).readonly(true),
+            data_disk: QemuDrive::new(data_disk),
+            extra_drives: Vec::new(),
+            overlay_dir: env::temp_dir().to_string_lossy().to_string(),
+            kernel,
+            manifest,
             agent_sock_path: None,
             qmp_sock_path: None,
             volatile: false,
             network: true,
             audio: true,
             virgl: false,
+            headless: false,
+            iothreads: false,
+            blk_multiqueue: false,
             shared_dirs: Vec::new(),
+            warm_boot: false,
+            shared_dir_hotplug: false,
+            usb: false,
+            tablet: false,
+            balloon: false,
+            balloon_stats_interval: 0,
+            cgroup_limits: None,
+            sandbox: false,
+            bubblewrap: None,
+            sandbox_binds: Vec::new(),
+            pvpanic: false,
+            watchdog: None,
+            qemu_log: None,
+            serial_log: None,
+            crash_dir: None,
+            virtiofsd: "/usr/libexec/virtiofsd".to_string(),
         }
     }
 
@@ -86,13 +252,118 @@ impl QemuRunner {
         self
     }
 
+    // Exposes the vCPUs to the guest as "sockets" sockets of "cores" cores
+    // with "threads" threads each, which must add up to vcpu_num.
+    pub fn smp_topology(mut self, sockets: u32, cores: u32, threads: u32) -> Self {
+        self.smp_topology = Some((sockets, cores, threads));
+        self
+    }
+
+    // Pins the vCPU threads to these host CPUs once QEMU is started, see
+    // QemuVm::pin_vcpus.
+    pub fn vcpu_affinity(mut self, cpus: Vec<usize>) -> Self {
+        self.vcpu_affinity = cpus;
+        self
+    }
+
+    // Pins the rest of the QEMU threads to these host CPUs, keeping them
+    // off the ones reserved for the vCPUs.
+    pub fn emulator_affinity(mut self, cpus: Vec<usize>) -> Self {
+        self.emulator_affinity = cpus;
+        self
+    }
+
     pub fn ram_mb(mut self, mb: u32) -> Self {
         self.ram_mb = mb;
         self
     }
 
+    // Adds a virtio-mem device able to grow the guest from ram_mb up to
+    // "mb". It starts empty and is resized at runtime, see VirtioMemPolicy.
+    pub fn maxmem_mb(mut self, mb: u32) -> Self {
+        self.maxmem_mb = Some(mb);
+        self
+    }
+
+    // Whether the guest memory may be merged by KSM with identical pages
+    // of other VMs booted from the same template. If unset, QEMU's default
+    // applies. KSM only merges private anonymous memory, so this has no
+    // effect with warm boot or shared dir hotplug, whose memory is shared.
+    pub fn mem_merge(mut self, mem_merge: bool) -> Self {
+        self.mem_merge = Some(mem_merge);
+        self
+    }
+
+    // Like kernel and template_drive, this drops the manifest of the
+    // template discovered by new(), which doesn't describe this image.
     pub fn template(mut self, template: String) -> Self {
-        self.template = template;
+        self.template = QemuDrive::new(template).readonly(true);
+        self.manifest = None;
+        self
+    }
+
+    pub fn kernel(mut self, kernel: String) -> Self {
+        self.kernel = kernel;
+        self.manifest = None;
+        self
+    }
+
+    // Uses the image, kernel and manifest of a discovered template.
+    pub fn template_info(mut self, info: &TemplateInfo) -> Self {
+        self.template = QemuDrive::new(info.template.to_string()).readonly(true);
+        self.kernel = info.kernel.to_string();
+        self.manifest = info.manifest.clone();
+        self
+    }
+
+    pub fn template_drive(mut self, drive: QemuDrive) -> Self {
+        self.template = drive;
+        self.manifest = None;
+        self
+    }
+
+    // Manifest with the expected digests of the template and kernel,
+    // verified by run() before starting the VM. Must be set after them,
+    // as template, kernel and template_drive clear it.
+    pub fn manifest(mut self, path: String) -> Self {
+        self.manifest = Some(path);
+        self
+    }
+
+    pub fn data_disk_drive(mut self, drive: QemuDrive) -> Self {
+        self.data_disk = drive;
+        self
+    }
+
+    pub fn extra_drive(mut self, drive: QemuExtraDrive) -> Self {
+        self.extra_drives.push(drive);
+        self
+    }
+
+    // Returns the extra drives that must be mounted by the agent, in the
+    // form expected by AgentHost::request_drive_mount.
+    pub fn get_extra_drive_mounts(&self) -> Vec<QemuDriveMount> {
+        let mut mounts = Vec::new();
+        for (i, extra) in self.extra_drives.iter().enumerate() {
+            if let Some(mount_point) = extra.get_mount_point() {
+                mounts.push(QemuDriveMount {
+                    role: extra.get_role().clone(),
+                    serial: format!("extra{}", i),
+                    mount_point: mount_point.to_string(),
+                    readonly: extra.get_drive().is_readonly(),
+                    mkfs: extra.get_scratch_size_mb().is_some(),
+                    bus: QemuDriveBus::Virtio,
+                });
+            }
+        }
+        mounts
+    }
+
+    // Directory where the temporary overlays for the template and, if
+    // volatile, the data disk are created, in a subdirectory for each VM.
+    // Pointing it to a tmpfs keeps the writes off the host's disk.
+    pub fn overlay_dir(mut self, dir: String) -> Self {
+        self.overlay_dir = dir;
         self
     }
 
@@ -111,6 +382,7 @@ impl QemuRunner {
         self
     }
 
+    // Plays the guest's audio through PulseAudio.
     pub fn audio(mut self, audio: bool) -> Self {
         self.audio = audio;
         self
@@ -126,6 +398,27 @@ impl QemuRunner {
         self
     }
 
+    // Runs without a window. The console can still be captured with
+    // QmpConn::screendump, rendered through EGL if virgl is enabled.
+    pub fn headless(mut self, headless: bool) -> Self {
+        self.headless = headless;
+        self
+    }
+
+    // Runs the template and data disk devices on their own iothreads,
+    // instead of QEMU's main loop. virtio-9p has no iothread support, so
+    // shared dirs keep being served from the main loop.
+    pub fn iothreads(mut self, iothreads: bool) -> Self {
+        self.iothreads = iothreads;
+        self
+    }
+
+    // Gives each virtio-blk device one request queue per vCPU.
+    pub fn blk_multiqueue(mut self, blk_multiqueue: bool) -> Self {
+        self.blk_multiqueue = blk_multiqueue;
+        self
+    }
+
     pub fn shared_dir(
         mut self,
         dir_type: QemuSharedDirType,
@@ -139,50 +432,704 @@ impl QemuRunner {
             source,
             tag: format!("shareddir{}", lastdir),
             readonly,
+            transport: QemuSharedDirTransport::Virtio9p,
         });
         self
     }
 
     pub fn get_shared_dirs(self) -> Vec<QemuSharedDir> {
+        let transport = if self.warm_boot {
+            QemuSharedDirTransport::Virtiofs
+        } else {
+            QemuSharedDirTransport::Virtio9p
+        };
+
         self.shared_dirs
+            .into_iter()
+            .map(|dir| QemuSharedDir { transport, ..dir })
+            .collect()
+    }
+
+    // Instead of booting the template on each launch, start() restores a
+    // "warm" VM state saved right after the agent became ready, and then
+    // hot-plugs the data disk and shared dirs. The state is created on
+    // first use and reused by every VM with the same template, kernel and
+    // machine configuration. Shared dirs are served through virtiofs, as
+    // virtio-9p devices can't be hot-plugged, and the agent must mount the
+    // data disk, as described by get_data_disk_mount.
+    //
+    // Every VM restored from the same state starts with the same guest RNG
+    // state, so the agent should reseed it, and with the clock of the time
+    // the state was saved, so the launcher should call request_time_sync
+    // on the agent once it's connected. Warm-booted VMs can't be saved.
+    pub fn warm_boot(mut self, warm_boot: bool) -> Self {
+        self.warm_boot = warm_boot;
+        self
+    }
+
+    // Allows shared dirs to be added to the running VM with
+    // QemuVm::add_shared_dir. Implied by warm_boot.
+    pub fn shared_dir_hotplug(mut self, shared_dir_hotplug: bool) -> Self {
+        self.shared_dir_hotplug = shared_dir_hotplug;
+        self
+    }
+
+    // Adds a USB controller, so USB mass-storage drives can be attached
+    // with QemuVm::add_drive.
+    pub fn usb(mut self, usb: bool) -> Self {
+        self.usb = usb;
+        self
+    }
+
+    // Adds an absolute pointing device, which keeps the guest pointer in
+    // sync with the host's and is needed by input::move_to and click_at.
+    pub fn tablet(mut self, tablet: bool) -> Self {
+        self.tablet = tablet;
+        self
+    }
+
+    // Adds a virtio-balloon device, with free page reporting so the pages
+    // freed by the guest are returned to the host.
+    pub fn balloon(mut self, balloon: bool) -> Self {
+        self.balloon = balloon;
+        self
+    }
+
+    // Has the guest report its memory stats every "secs" seconds, which
+    // BalloonPolicy relies on. Zero disables polling.
+    pub fn balloon_stats_interval(mut self, secs: u32) -> Self {
+        self.balloon_stats_interval = secs;
+        self
+    }
+
+    // Runs QEMU and its helpers in a cgroup of their own, limited by
+    // "limits". It needs cgroup v2, with the launcher's cgroup and its cpu,
+    // memory and io controllers delegated to the user, see
+    // Cgroup::path_for. The launcher then moves itself to a leaf of it.
+    pub fn cgroup_limits(mut self, limits: CgroupLimits) -> Self {
+        self.cgroup_limits = Some(limits);
+        self
+    }
+
+    // Enables QEMU's seccomp filter, denying it from gaining privileges,
+    // spawning processes and changing its scheduling or resource limits.
+    pub fn sandbox(mut self, sandbox: bool) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    // Runs QEMU under the bubblewrap binary at "path", in a mount namespace
+    // exposing only the host libraries, the VM's images, sockets and
+    // shared dirs, and the overlay dir.
+    pub fn bubblewrap(mut self, path: String) -> Self {
+        self.bubblewrap = Some(path);
+        self
+    }
+
+    // Exposes "path" to QEMU when running under bubblewrap, which is needed
+    // for drives hot-plugged from outside the overlay dir.
+    pub fn sandbox_bind(mut self, path: String, readonly: bool) -> Self {
+        self.sandbox_binds.push((path, readonly));
+        self
+    }
+
+    // Adds a pvpanic device, through which the guest kernel reports its
+    // panics. The VM is then paused instead of shut down, so a crash
+    // report can be written with QemuVm::write_crash_report.
+    pub fn pvpanic(mut self, pvpanic: bool) -> Self {
+        self.pvpanic = pvpanic;
+        self
+    }
+
+    // Adds an i6300esb watchdog, which QEMU fires with "action" if the
+    // guest kernel hangs and stops feeding it. QEMU emits a WATCHDOG event
+    // in any case.
+    pub fn watchdog(mut self, action: QemuWatchdogAction) -> Self {
+        self.watchdog = Some(action);
+        self
+    }
+
+    // Sends QEMU's stderr to "path", instead of discarding it.
+    pub fn qemu_log(mut self, path: String) -> Self {
+        self.qemu_log = Some(path);
+        self
+    }
+
+    // Writes the guest kernel console, on its first serial port, to "path".
+    pub fn serial_log(mut self, path: String) -> Self {
+        self.serial_log = Some(path);
+        self
+    }
+
+    // Where crash reports are written, defaulting to the "crashes" dir in
+    // the flatkvm cache dir.
+    pub fn crash_dir(mut self, path: String) -> Self {
+        self.crash_dir = Some(path);
+        self
+    }
+
+    pub fn virtiofsd_path(mut self, path: String) -> Self {
+        self.virtiofsd = path;
+        self
+    }
+
+    pub fn get_data_disk_mount(&self) -> QemuDriveMount {
+        QemuDriveMount {
+            role: QemuDriveRole::Data,
+            serial: "datadisk".to_string(),
+            mount_point: String::new(),
+            readonly: self.data_disk.is_readonly(),
+            mkfs: false,
+            bus: QemuDriveBus::Virtio,
+        }
+    }
+
+    // Describes the disks and machine configuration a saved state taken
+    // with this runner is bound to.
+    pub(crate) fn saved_state_info(&self) -> Result<SavedStateInfo, String> {
+        // The data disk is always hashed in full, as a copy of another disk
+        // with its mtime preserved would be given the cached digest.
+        let data_disk_sha256 = file_sha256(self.data_disk.get_path())?;
+        let mut cache = DigestCache::open_default();
+        let template_sha256 = cache.file_sha256(self.template.get_path())?;
+        let kernel_sha256 = cache.file_sha256(&self.kernel)?;
+        let _ = cache.save();
+
+        Ok(SavedStateInfo {
+            data_disk: self.data_disk.get_path().to_string(),
+            data_disk_sha256,
+            template_sha256,
+            kernel_sha256,
+            vcpu_num: self.vcpu_num,
+            ram_mb: self.ram_mb,
+            maxmem_mb: self.maxmem_mb,
+        })
+    }
+
+    // The contents of volatile drives are lost when QEMU exits, so a VM
+    // using them can't be restored from a saved state. Neither can a
+    // warm-booted VM, whose hot-plugged devices restore wouldn't recreate.
+    pub(crate) fn check_saveable(&self) -> Result<(), String> {
+        if self.warm_boot {
+            return Err("can't save the state of a warm-booted VM".to_string());
+        }
+
+        let volatile_extras = self
+            .extra_drives
+            .iter()
+            .any(|extra| extra.is_volatile() || extra.get_scratch_size_mb().is_some());
+
+        if self.volatile || volatile_extras {
+            Err("can't save the state of a VM with volatile drives".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn get_virtiofsd(&self) -> &str {
+        &self.virtiofsd
+    }
+
+    pub(crate) fn get_qemu_log(&self) -> Option<&str> {
+        self.qemu_log.as_deref()
+    }
+
+    pub(crate) fn get_serial_log(&self) -> Option<&str> {
+        self.serial_log.as_deref()
+    }
+
+    pub(crate) fn get_crash_dir(&self) -> Option<PathBuf> {
+        match &self.crash_dir {
+            Some(dir) => Some(PathBuf::from(dir)),
+            None => Some(cache_dir()?.join("crashes")),
+        }
+    }
+
+    pub(crate) fn get_cgroup(&self) -> Option<Cgroup> {
+        self.cgroup_limits.as_ref()?;
+        Cgroup::path_for(&self.name).ok().map(Cgroup::open)
+    }
+
+    pub(crate) fn virtiofsd_sock_path(&self, tag: &str) -> String {
+        self.vm_runtime_dir()
+            .join(format!("{}.sock", tag))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    // The files of each VM go in directories of their own, so only those
+    // are exposed to QEMU when it runs under bubblewrap.
+    fn vm_overlay_dir(&self) -> PathBuf {
+        Path::new(&self.overlay_dir).join(format!("flatkvm-{}-{}", self.name, process::id()))
+    }
+
+    fn vm_runtime_dir(&self) -> PathBuf {
+        runtime_dir().join(format!("flatkvm-{}-{}", self.name, process::id()))
+    }
+
+    fn create_vm_dirs(&self) -> Result<(), String> {
+        for dir in &[self.vm_overlay_dir(), self.vm_runtime_dir()] {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)
+                .map_err(|err| format!("{}: {}", dir.display(), err))?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn blk_iothread(&self, node_name: &str) -> Option<String> {
+        if self.iothreads {
+            Some(format!("iothread-{}", node_name))
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn blk_num_queues(&self) -> Option<u32> {
+        if self.blk_multiqueue {
+            Some(self.vcpu_num)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn overlay_path(&self, node_name: &str) -> String {
+        self.vm_overlay_dir()
+            .join(format!("{}.qcow2", node_name))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    // Creates the temporary overlay that receives the writes to a volatile
+    // drive, returning its path.
+    pub(crate) fn temp_overlay(
+        &self,
+        drive: &QemuDrive,
+        node_name: &str,
+    ) -> Result<String, String> {
+        if drive.get_secret().is_some() {
+            return Err(format!(
+                "{}: encrypted drives can't be volatile",
+                drive.get_path()
+            ));
+        }
+
+        let overlay = self.overlay_path(node_name);
+        create_overlay(drive.get_path(), drive.get_format(), &overlay)?;
+        Ok(overlay)
+    }
+
+    fn smp_arg(&self) -> Result<String, String> {
+        match self.smp_topology {
+            Some((sockets, cores, threads))
+                if sockets
+                    .checked_mul(cores)
+                    .and_then(|n| n.checked_mul(threads))
+                    != Some(self.vcpu_num) =>
+            {
+                Err(format!(
+                    "SMP topology {}x{}x{} doesn't match {} vCPUs",
+                    sockets, cores, threads, self.vcpu_num
+                ))
+            }
+            Some((sockets, cores, threads)) => Ok(format!(
+                "{},sockets={},cores={},threads={}",
+                self.vcpu_num, sockets, cores, threads
+            )),
+            None => Ok(self.vcpu_num.to_string()),
+        }
+    }
+
+    fn memory_size_arg(&self) -> Result<String, String> {
+        match self.maxmem_mb {
+            Some(maxmem_mb) if maxmem_mb <= self.ram_mb => Err(format!(
+                "maxmem ({}m) must be larger than ram ({}m)",
+                maxmem_mb, self.ram_mb
+            )),
+            Some(maxmem_mb) => Ok(format!("{}m,maxmem={}m", self.ram_mb, maxmem_mb)),
+            None => Ok(format!("{}m", self.ram_mb)),
+        }
+    }
+
+    fn memory_backend_args(&self, id: &str, size_mb: u32) -> String {
+        // vhost-user devices, such as virtiofs, need the guest memory to be
+        // shared with their backend.
+        if self.warm_boot || self.shared_dir_hotplug {
+            format!(
+                " -object memory-backend-memfd,id={},size={}m,share=on",
+                id, size_mb
+            )
+        } else {
+            format!(" -object memory-backend-ram,id={},size={}m", id, size_mb)
+        }
+    }
+
+    fn memory_args(&self) -> String {
+        let mut args = String::new();
+
+        // Memory backends inherit the machine's setting.
+        if let Some(mem_merge) = self.mem_merge {
+            args.push_str(if mem_merge {
+                " -machine mem-merge=on"
+            } else {
+                " -machine mem-merge=off"
+            });
+        }
+
+        if self.warm_boot || self.shared_dir_hotplug {
+            args.push_str(&self.memory_backend_args("mem0", self.ram_mb));
+            args.push_str(" -machine memory-backend=mem0");
+        }
+        if let Some(maxmem_mb) = self.maxmem_mb {
+            args.push_str(&self.memory_backend_args("vmem0-mem", maxmem_mb - self.ram_mb));
+            args.push_str(" -device virtio-mem-pci,id=vmem0,memdev=vmem0-mem,requested-size=0");
+        }
+
+        args
+    }
+
+    // Builds the bubblewrap arguments exposing what QEMU needs from the
+    // host. The PID namespace is kept, so QMP reports host thread ids.
+    fn bubblewrap_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = vec![
+            "--die-with-parent",
+            "--unshare-user-try",
+            "--unshare-ipc",
+            "--unshare-uts",
+            "--unshare-cgroup-try",
+            "--ro-bind",
+            "/usr",
+            "/usr",
+            "--proc",
+            "/proc",
+            "--dev",
+            "/dev",
+            "--dev-bind",
+            "/dev/kvm",
+            "/dev/kvm",
+            "--tmpfs",
+            "/tmp",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+
+        for dir in &["/bin", "/sbin", "/lib", "/lib64"] {
+            match fs::read_link(dir) {
+                Ok(target) => {
+                    let target = target.to_string_lossy().to_string();
+                    args.extend(vec!["--symlink".to_string(), target, dir.to_string()]);
+                }
+                Err(_) if Path::new(dir).exists() => bwrap_bind(&mut args, dir, true),
+                Err(_) => (),
+            }
+        }
+        for file in &[
+            "/etc/ld.so.cache",
+            "/etc/passwd",
+            "/etc/group",
+            "/etc/nsswitch.conf",
+            "/etc/resolv.conf",
+            "/etc/hosts",
+            "/etc/fonts",
+        ] {
+            if Path::new(file).exists() {
+                bwrap_bind(&mut args, file, true);
+            }
+        }
+        if !self.network {
+            args.push("--unshare-net".to_string());
+        }
+        if self.virgl && Path::new("/dev/dri").exists() {
+            args.extend(vec![
+                "--dev-bind".to_string(),
+                "/dev/dri".to_string(),
+                "/dev/dri".to_string(),
+            ]);
+        }
+
+        let runtime_dir = runtime_dir();
+        // The sockets and serial log are passed as fds instead, see
+        // spawn_qemu, so their directories aren't exposed.
+        bwrap_bind(&mut args, &self.vm_runtime_dir().to_string_lossy(), false);
+        bwrap_bind(&mut args, &self.vm_overlay_dir().to_string_lossy(), false);
+        if !self.headless {
+            if Path::new("/tmp/.X11-unix").exists() {
+                bwrap_bind(&mut args, "/tmp/.X11-unix", true);
+            }
+            if let Ok(xauthority) = env::var("XAUTHORITY") {
+                bwrap_bind(&mut args, &xauthority, true);
+            }
+            if let Ok(display) = env::var("WAYLAND_DISPLAY") {
+                bwrap_bind(
+                    &mut args,
+                    &runtime_dir.join(display).to_string_lossy(),
+                    true,
+                );
+            }
+        }
+        if self.audio && runtime_dir.join("pulse").exists() {
+            bwrap_bind(
+                &mut args,
+                &runtime_dir.join("pulse").to_string_lossy(),
+                true,
+            );
+        }
+        bwrap_bind(&mut args, &self.kernel, true);
+        bwrap_bind(&mut args, self.template.get_path(), true);
+        // Even when started without them, the data disk and extra drives
+        // may be hot-plugged later on, as with warm boot.
+        let mut drives = vec![(&self.data_disk, self.volatile)];
+        for extra in &self.extra_drives {
+            if extra.get_scratch_size_mb().is_none() {
+                drives.push((extra.get_drive(), extra.is_volatile()));
+            }
+        }
+        for dir in &self.shared_dirs {
+            bwrap_bind(&mut args, &dir.source, dir.readonly);
+        }
+        for (drive, volatile) in drives {
+            bwrap_bind(&mut args, drive.get_path(), drive.is_readonly() || volatile);
+            if let Some(QemuDriveSecret::File(path)) = drive.get_secret() {
+                bwrap_bind(&mut args, path, true);
+            }
+        }
+        for (path, readonly) in &self.sandbox_binds {
+            bwrap_bind(&mut args, path, *readonly);
+        }
+
+        args
+    }
+
+    fn drive_args(
+        &self,
+        drive: &QemuDrive,
+        node_name: &str,
+        serial: Option<&str>,
+        overlay: Option<&str>,
+    ) -> Result<String, String> {
+        drive.validate()?;
+
+        let mut args = drive.blockdev_args(node_name, overlay);
+
+        let iothread = self.blk_iothread(node_name);
+        if let Some(iothread) = &iothread {
+            args.push_str(&format!(" -object iothread,id={}", iothread));
+        }
+        args.push_str(&drive.device_args(
+            node_name,
+            serial,
+            iothread.as_deref(),
+            self.blk_num_queues(),
+        ));
+
+        Ok(args)
+    }
+
+    fn extra_drive_args(&self, index: usize, extra: &QemuExtraDrive) -> Result<String, String> {
+        let node_name = format!("extra{}", index);
+
+        match extra.get_scratch_size_mb() {
+            Some(size_mb) => {
+                let path = self.overlay_path(&node_name);
+                create_image(&path, QemuDriveFormat::Qcow2, size_mb)?;
+                let drive = QemuDrive::new(path);
+                self.drive_args(&drive, &node_name, Some(&node_name), None)
+            }
+            None => {
+                let overlay = if extra.is_volatile() {
+                    Some(self.temp_overlay(extra.get_drive(), &node_name)?)
+                } else {
+                    None
+                };
+                self.drive_args(
+                    extra.get_drive(),
+                    &node_name,
+                    Some(&node_name),
+                    overlay.as_deref(),
+                )
+            }
+        }
+    }
+
+    // File descriptors carrying drive secrets, which must be inherited
+    // by the QEMU process.
+    fn secret_fds(&self) -> Vec<RawFd> {
+        let mut drives = vec![&self.template, &self.data_disk];
+        for extra in &self.extra_drives {
+            drives.push(extra.get_drive());
+        }
+
+        drives
+            .iter()
+            .filter_map(|drive| match drive.get_secret() {
+                Some(QemuDriveSecret::Fd(fd)) => Some(*fd),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Removes the temporary overlays, scratch images and sockets created
+    // for the VM. Must be called once the QEMU process has exited.
+    pub fn cleanup(&self) -> Result<(), String> {
+        for dir in &[self.vm_overlay_dir(), self.vm_runtime_dir()] {
+            match fs::remove_dir_all(dir) {
+                Ok(_) => (),
+                Err(ref err) if err.kind() == ErrorKind::NotFound => (),
+                Err(err) => return Err(format!("{}: {}", dir.display(), err)),
+            }
+        }
+
+        if let Some(cgroup) = self.get_cgroup() {
+            cgroup.remove()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn verify(&self) -> Result<(), IntegrityError> {
+        match &self.manifest {
+            Some(path) => {
+                let manifest = TemplateManifest::load(path).map_err(IntegrityError::Io)?;
+                verify_template(&manifest, self.template.get_path(), &self.kernel)
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn run(&self) -> Result<Child, LaunchError> {
+        self.launch(None, false)
     }
 
-    pub fn run(&self) -> Result<Child, String> {
+    // Starts QEMU. If "incoming_fd" is set, the VM state is loaded from it
+    // instead of booting, and the template overlay saved along with the
+    // state must already be in place. If "base_only" is set, the VM is
+    // started without the data disk, extra drives and shared dirs.
+    fn launch(&self, incoming_fd: Option<RawFd>, base_only: bool) -> Result<Child, LaunchError> {
+        if self.template.get_path().is_empty() || self.kernel.is_empty() {
+            return Err(LaunchError::Other(
+                "no compatible template found".to_string(),
+            ));
+        }
+        self.verify().map_err(LaunchError::Integrity)?;
+        for extra in &self.extra_drives {
+            extra.validate()?;
+        }
+
+        let result = self
+            .create_vm_dirs()
+            .and_then(|_| self.spawn_qemu(incoming_fd, base_only));
+        if result.is_err() {
+            // Don't leak the overlays and scratch images created so far.
+            let _ = self.cleanup();
+        }
+        result.map_err(LaunchError::Other)
+    }
+
+    // The command line needs QEMU 6.0 or newer, for -action, and avoids
+    // options removed since, such as -soundhw.
+    fn spawn_qemu(&self, incoming_fd: Option<RawFd>, base_only: bool) -> Result<Child, String> {
+        // Under bubblewrap, QEMU can't see the directories holding its
+        // sockets and serial log, so they're opened here and passed on.
+        let listen = |path: &String| {
+            let _ = fs::remove_file(path);
+            UnixListener::bind(path).map_err(|err| format!("{}: {}", path, err))
+        };
+        let (agent_listener, qmp_listener, serial_file) = match self.bubblewrap {
+            Some(_) => (
+                self.agent_sock_path.as_ref().map(listen).transpose()?,
+                self.qmp_sock_path.as_ref().map(listen).transpose()?,
+                self.serial_log
+                    .as_ref()
+                    .map(|path| File::create(path).map_err(|err| format!("{}: {}", path, err)))
+                    .transpose()?,
+            ),
+            None => (None, None, None),
+        };
+
         let uid = match env::var("UID") {
             Ok(uid) => uid,
             Err(_) => "1000".to_string(),
         };
 
-        let mut cmdline = format!("-nodefaults -name {} -machine pc,accel=kvm,kernel_irqchip -cpu host,pmu=off -smp {} -m {}m -drive if=virtio,file={},snapshot=on -kernel {} -append \"root=/dev/vda quiet net.ifnames=0 flatkvm_uid={}\" -device virtio-vga -display",
+        let mut cmdline = format!("-nodefaults -name {} -machine pc,accel=kvm,kernel_irqchip -cpu host,pmu=off -smp {} -m {} -kernel {} -append \"root=/dev/vda quiet net.ifnames=0 flatkvm_uid={}{}\" -device virtio-vga -display",
                                   self.name,
-                                  self.vcpu_num,
-                                  self.ram_mb,
-                                  self.template,
+                                  self.smp_arg()?,
+                                  self.memory_size_arg()?,
                                   self.kernel,
-                                  uid);
+                                  uid,
+                                  if self.serial_log.is_some() { " console=ttyS0" } else { "" });
 
-        if self.virgl {
-            cmdline.push_str(" gtk,gl=on")
-        } else {
-            cmdline.push_str(" gtk")
+        match (self.headless, self.virgl) {
+            (true, true) => cmdline.push_str(" egl-headless"),
+            (true, false) => cmdline.push_str(" none"),
+            (false, true) => cmdline.push_str(" gtk,gl=on"),
+            (false, false) => cmdline.push_str(" gtk"),
         }
-        cmdline.push_str(&format!(" -drive if=virtio,file={}", self.data_disk));
-        if self.volatile {
-            cmdline.push_str(",snapshot=on")
+        cmdline.push_str(&self.memory_args());
+        let template_overlay = match incoming_fd {
+            Some(_) => self.overlay_path("template"),
+            None => self.temp_overlay(&self.template, "template")?,
+        };
+        cmdline.push_str(&self.drive_args(
+            &self.template,
+            "template",
+            None,
+            Some(&template_overlay),
+        )?);
+        if !base_only {
+            let data_disk_overlay = if self.volatile {
+                Some(self.temp_overlay(&self.data_disk, "datadisk")?)
+            } else {
+                None
+            };
+            cmdline.push_str(&self.drive_args(
+                &self.data_disk,
+                "datadisk",
+                None,
+                data_disk_overlay.as_deref(),
+            )?);
+            for (i, extra) in self.extra_drives.iter().enumerate() {
+                cmdline.push_str(&self.extra_drive_args(i, extra)?);
+            }
         }
         if let Some(agent_sock_path) = &self.agent_sock_path {
-            cmdline.push_str(&format!(" -device virtio-serial -chardev socket,path={},server,id=flatkvm-agent,nowait -device virtserialport,chardev=flatkvm-agent,name=org.flatkvm.port.0", agent_sock_path));
+            let addr = match &agent_listener {
+                Some(listener) => format!("fd={}", listener.as_raw_fd()),
+                None => format!("path={}", agent_sock_path),
+            };
+            cmdline.push_str(&format!(" -device virtio-serial -chardev socket,{},server,id=flatkvm-agent,nowait -device virtserialport,chardev=flatkvm-agent,name=org.flatkvm.port.0", addr));
         }
         if let Some(qmp_sock_path) = &self.qmp_sock_path {
-            cmdline.push_str(&format!(" -qmp unix:{},server", qmp_sock_path));
+            match &qmp_listener {
+                Some(listener) => cmdline.push_str(&format!(
+                    " -chardev socket,fd={},server,id=flatkvm-qmp -mon chardev=flatkvm-qmp,mode=control",
+                    listener.as_raw_fd()
+                )),
+                None => cmdline.push_str(&format!(" -qmp unix:{},server", qmp_sock_path)),
+            }
         }
         if self.network {
             cmdline.push_str(" -net nic,model=virtio -net user");
         }
         if self.audio {
-            cmdline.push_str(" -soundhw ac97");
+            cmdline.push_str(" -audiodev pa,id=flatkvm-audio -device AC97,audiodev=flatkvm-audio");
         }
-        for dir in &self.shared_dirs {
+        if self.usb {
+            cmdline.push_str(" -device qemu-xhci,id=xhci");
+        }
+        if self.tablet {
+            cmdline.push_str(" -device virtio-tablet-pci");
+        }
+        if self.balloon {
+            cmdline.push_str(" -device virtio-balloon-pci,id=balloon0,free-page-reporting=on");
+        }
+        for dir in self.shared_dirs.iter().filter(|_| !base_only) {
             cmdline.push_str(&format!(
                 " -virtfs local,id={},path={},security_model=none,mount_tag={}",
                 dir.tag, dir.source, dir.tag
@@ -191,17 +1138,299 @@ impl QemuRunner {
                 cmdline.push_str(",readonly");
             }
         }
+        if self.pvpanic {
+            cmdline.push_str(" -device pvpanic -action panic=pause");
+        }
+        if let Some(action) = self.watchdog {
+            cmdline.push_str(&format!(
+                " -device i6300esb -action watchdog={}",
+                action.as_str()
+            ));
+        }
+        match (&serial_file, &self.serial_log) {
+            (Some(file), _) => cmdline.push_str(&format!(
+                " -add-fd fd={},set=1 -serial file:/dev/fdset/1",
+                file.as_raw_fd()
+            )),
+            (None, Some(serial_log)) => cmdline.push_str(&format!(" -serial file:{}", serial_log)),
+            (None, None) => (),
+        }
+        if self.sandbox {
+            cmdline.push_str(
+                " -sandbox on,obsolete=deny,elevateprivileges=deny,spawn=deny,resourcecontrol=deny",
+            );
+        }
+        if let Some(fd) = incoming_fd {
+            cmdline.push_str(&format!(" -incoming fd:{}", fd));
+        }
         let args = match split(&cmdline) {
             Some(args) => args,
             None => return Err("can't format arguments".to_string()),
         };
 
-        Command::new("qemu-system-x86_64")
-            .args(&args)
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .spawn()
-            .map_err(|err| err.to_string())
+        let mut inherited_fds = self.secret_fds();
+        inherited_fds.extend(incoming_fd);
+        inherited_fds.extend(agent_listener.iter().map(AsRawFd::as_raw_fd));
+        inherited_fds.extend(qmp_listener.iter().map(AsRawFd::as_raw_fd));
+        inherited_fds.extend(serial_file.iter().map(AsRawFd::as_raw_fd));
+        let mut command = match &self.bubblewrap {
+            Some(bwrap) => {
+                let mut command = Command::new(bwrap);
+                command
+                    .args(self.bubblewrap_args())
+                    .arg("qemu-system-x86_64");
+                command
+            }
+            None => Command::new("qemu-system-x86_64"),
+        };
+        let stderr = match &self.qemu_log {
+            Some(path) => {
+                Stdio::from(File::create(path).map_err(|err| format!("{}: {}", path, err))?)
+            }
+            None => Stdio::null(),
+        };
+        command.args(&args).stdout(Stdio::null()).stderr(stderr);
+        if let Some(limits) = &self.cgroup_limits {
+            let cgroup = Cgroup::create(Cgroup::path_for(&self.name)?, limits)?;
+            cgroup.enter_on_spawn(&mut command)?;
+        }
+        unsafe {
+            command.pre_exec(move || {
+                for fd in &inherited_fds {
+                    if libc::fcntl(*fd, libc::F_SETFD, 0) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        command.spawn().map_err(|err| err.to_string())
+    }
+
+    fn connect(&self, mut child: Child) -> Result<QemuVm, String> {
+        let qmp = match self
+            .get_qmp_conn()
+            .and_then(|qmp| qmp.initialize().map(|_| qmp).map_err(|err| err.to_string()))
+            .and_then(|qmp| self.setup_qmp(&qmp).map(|_| qmp))
+        {
+            Ok(qmp) => qmp,
+            Err(err) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(err);
+            }
+        };
+
+        let mut vm = QemuVm::new(child, qmp);
+        vm.set_cgroup(self.get_cgroup());
+        Ok(vm)
+    }
+
+    // Configures the devices that can only be set up through QMP.
+    fn setup_qmp(&self, qmp: &QmpConn) -> Result<(), String> {
+        if !self.vcpu_affinity.is_empty() {
+            pin_vcpu_threads(qmp, &self.vcpu_affinity)?;
+        }
+        if !self.emulator_affinity.is_empty() {
+            pin_emulator_threads(qmp, &self.emulator_affinity)?;
+        }
+        if self.balloon && self.balloon_stats_interval > 0 {
+            qmp.qom_set(
+                BALLOON_QOM_PATH,
+                "guest-stats-polling-interval",
+                json!(self.balloon_stats_interval),
+            )?;
+        }
+        Ok(())
+    }
+
+    // Runs QEMU and sets up its QMP connection, which must be configured.
+    pub fn start(&self) -> Result<QemuVm, String> {
+        if self.qmp_sock_path.is_none() {
+            return Err("QMP not configured".to_string());
+        }
+
+        if self.warm_boot {
+            return self.start_warm();
+        }
+
+        let child = self.run()?;
+        self.connect(child)
+    }
+
+    fn warm_state_dir(&self) -> Result<PathBuf, String> {
+        let mut cache = DigestCache::open_default();
+        let template_sha256 = cache.file_sha256(self.template.get_path())?;
+        let kernel_sha256 = cache.file_sha256(&self.kernel)?;
+        let _ = cache.save();
+
+        let key = format!(
+            "{}:{}:{}:{:?}:{}:{}:{}:{}:{}:{}:{}:{}:{}:{}:{}:{:?}:{}",
+            template_sha256,
+            kernel_sha256,
+            self.vcpu_num,
+            self.smp_topology,
+            self.ram_mb,
+            self.maxmem_mb.unwrap_or(0),
+            self.network,
+            self.audio,
+            self.virgl,
+            self.iothreads,
+            self.blk_multiqueue,
+            self.usb,
+            self.tablet,
+            self.balloon,
+            self.pvpanic,
+            self.watchdog,
+            self.serial_log.is_some()
+        );
+        match cache_dir() {
+            Some(dir) => Ok(dir.join("warm").join(data_sha256(key.as_bytes()))),
+            None => Err("can't find the cache directory".to_string()),
+        }
+    }
+
+    // Boots the template, waits for the agent to be ready and saves the
+    // VM state to "dir".
+    // Other launchers may be doing the same, so the state is saved to a
+    // directory of our own and only then published.
+    fn create_warm_state(&self, dir: &Path) -> Result<(), String> {
+        let tmp_dir = dir.with_extension(format!("tmp-{}", process::id()));
+        let _ = fs::remove_dir_all(&tmp_dir);
+        fs::create_dir_all(&tmp_dir).map_err(|err| err.to_string())?;
+
+        let child = self.launch(None, true)?;
+        let mut vm = self.connect(child)?;
+        let template_overlay = self.overlay_path("template");
+        let result = self
+            .get_agent()
+            .and_then(|mut agent| agent.initialize())
+            .and_then(|_| vm.save_base_state(&template_overlay, &tmp_dir, WARM_STATE_TIMEOUT));
+        let _ = fs::remove_file(&template_overlay);
+        if let Err(err) = result {
+            let _ = vm.shutdown(Duration::from_secs(1));
+            let _ = fs::remove_dir_all(&tmp_dir);
+            return Err(err);
+        }
+
+        match fs::rename(&tmp_dir, dir) {
+            Ok(_) => Ok(()),
+            // Another launcher published its state first, which is just as
+            // good as ours.
+            Err(_) if dir.join(STATE_FILE).exists() => {
+                let _ = fs::remove_dir_all(&tmp_dir);
+                Ok(())
+            }
+            Err(err) => {
+                let _ = fs::remove_dir_all(&tmp_dir);
+                Err(err.to_string())
+            }
+        }
+    }
+
+    fn start_warm(&self) -> Result<QemuVm, String> {
+        if !self.extra_drives.is_empty() {
+            return Err("extra drives can't be used with warm boot".to_string());
+        }
+        // The data disk is hot-plugged, which fd secrets don't support.
+        if let Some(QemuDriveSecret::Fd(_)) = self.data_disk.get_secret() {
+            return Err("data disks with fd secrets can't be used with warm boot".to_string());
+        }
+
+        let dir = self.warm_state_dir()?;
+        if !dir.join(STATE_FILE).exists() {
+            self.create_warm_state(&dir)?;
+        }
+
+        self.create_vm_dirs()?;
+        fs::copy(
+            dir.join(STATE_TEMPLATE_OVERLAY_FILE),
+            self.overlay_path("template"),
+        )
+        .map_err(|err| err.to_string())?;
+        let state = File::open(dir.join(STATE_FILE)).map_err(|err| err.to_string())?;
+        let child = self.launch(Some(state.as_raw_fd()), true)?;
+        let mut vm = self.connect(child)?;
+
+        let result = vm.wait_incoming(WARM_STATE_TIMEOUT).and_then(|_| {
+            let overlay = if self.volatile {
+                Some(self.temp_overlay(&self.data_disk, "datadisk")?)
+            } else {
+                None
+            };
+            vm.hotplug_drive(
+                self,
+                &self.data_disk,
+                "datadisk",
+                "datadisk",
+                QemuDriveBus::Virtio,
+                overlay.as_deref(),
+            )?;
+            for dir in &self.shared_dirs {
+                vm.hotplug_shared_dir(self, &dir.tag, &dir.source, dir.readonly)?;
+            }
+            Ok(())
+        });
+        if let Err(err) = result {
+            let _ = vm.shutdown(Duration::from_secs(1));
+            return Err(err);
+        }
+
+        Ok(vm)
+    }
+
+    // Restarts a VM saved with QemuVm::save_state, using this same
+    // configuration. The saved state is consumed. The agent connection
+    // must be set up again with get_agent, but without waiting for the
+    // handshake, which already took place before the VM was saved. Fails
+    // if the state isn't loaded within "timeout".
+    pub fn restore(&self, dir: &str, timeout: Duration) -> Result<QemuVm, String> {
+        if self.qmp_sock_path.is_none() {
+            return Err("QMP not configured".to_string());
+        }
+        self.check_saveable()?;
+
+        let dir = Path::new(dir);
+        let data = fs::read_to_string(dir.join(STATE_INFO_FILE)).map_err(|err| err.to_string())?;
+        let saved: SavedStateInfo = serde_json::from_str(&data).map_err(|err| err.to_string())?;
+        let current = self.saved_state_info()?;
+        if saved.vcpu_num != current.vcpu_num
+            || saved.ram_mb != current.ram_mb
+            || saved.maxmem_mb != current.maxmem_mb
+            || saved.data_disk != current.data_disk
+        {
+            return Err("saved state doesn't match the VM configuration".to_string());
+        }
+        if saved.data_disk_sha256 != current.data_disk_sha256 {
+            return Err("data disk has been modified since the state was saved".to_string());
+        }
+        if saved.template_sha256 != current.template_sha256
+            || saved.kernel_sha256 != current.kernel_sha256
+        {
+            return Err("template or kernel has changed since the state was saved".to_string());
+        }
+
+        // The saved files are only removed once the VM is back up, so a
+        // failed restore can be retried.
+        let state = File::open(dir.join(STATE_FILE)).map_err(|err| err.to_string())?;
+        self.create_vm_dirs()?;
+        fs::copy(
+            dir.join(STATE_TEMPLATE_OVERLAY_FILE),
+            self.overlay_path("template"),
+        )
+        .map_err(|err| err.to_string())?;
+
+        let child = self.launch(Some(state.as_raw_fd()), false)?;
+        let mut vm = self.connect(child)?;
+        if let Err(err) = vm.wait_incoming(timeout) {
+            let _ = vm.shutdown(Duration::from_secs(1));
+            return Err(err);
+        }
+
+        let _ = fs::remove_file(dir.join(STATE_FILE));
+        let _ = fs::remove_file(dir.join(STATE_INFO_FILE));
+        let _ = fs::remove_file(dir.join(STATE_TEMPLATE_OVERLAY_FILE));
+        Ok(vm)
     }
 
     pub fn get_agent(&self) -> Result<AgentHost, String> {
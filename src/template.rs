@@ -0,0 +1,186 @@
+// flatkvm-qemu
+// Copyright (C) 2019  Sergio Lopez <slp@sinrega.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use serde_derive::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const TEMPLATE_FILE: &str = "template.qcow2";
+const KERNEL_FILE: &str = "vmlinuz.flatkvm";
+const METADATA_FILE: &str = "metadata.json";
+const MANIFEST_FILE: &str = "manifest.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TemplateMetadata {
+    pub version: String,
+    pub arch: String,
+    pub required_agent_version: String,
+}
+
+#[derive(Debug)]
+pub struct TemplateInfo {
+    pub name: String,
+    pub metadata: TemplateMetadata,
+    pub template: String,
+    pub kernel: String,
+    pub manifest: Option<String>,
+}
+
+impl TemplateInfo {
+    fn load(name: &str, dir: &Path) -> Option<TemplateInfo> {
+        let data = fs::read_to_string(dir.join(METADATA_FILE)).ok()?;
+        let metadata: TemplateMetadata = serde_json::from_str(&data).ok()?;
+
+        let template = dir.join(TEMPLATE_FILE);
+        let kernel = dir.join(KERNEL_FILE);
+        if !template.is_file() || !kernel.is_file() {
+            return None;
+        }
+        let manifest = dir.join(MANIFEST_FILE);
+
+        Some(TemplateInfo {
+            name: name.to_string(),
+            metadata,
+            template: template.to_string_lossy().to_string(),
+            kernel: kernel.to_string_lossy().to_string(),
+            manifest: if manifest.is_file() {
+                Some(manifest.to_string_lossy().to_string())
+            } else {
+                None
+            },
+        })
+    }
+
+    // Whether this template runs on this host and its agent can be
+    // driven by a host speaking "agent_version".
+    pub fn is_compatible(&self, agent_version: &str) -> bool {
+        self.metadata.arch == env::consts::ARCH
+            && compare_versions(&self.metadata.required_agent_version, agent_version)
+                != Ordering::Greater
+    }
+}
+
+fn parse_version(version: &str) -> Vec<u64> {
+    version
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0))
+        .collect()
+}
+
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let mut a = parse_version(a);
+    let mut b = parse_version(b);
+    let len = a.len().max(b.len());
+    a.resize(len, 0);
+    b.resize(len, 0);
+    a.cmp(&b)
+}
+
+// Directories searched for templates, in order of preference: the user's
+// data dir comes first, so its templates override the system ones.
+pub fn search_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+
+    match env::var("XDG_DATA_HOME") {
+        Ok(dir) if !dir.is_empty() => paths.push(PathBuf::from(dir)),
+        _ => {
+            if let Ok(home) = env::var("HOME") {
+                paths.push(PathBuf::from(home).join(".local/share"));
+            }
+        }
+    }
+
+    let data_dirs = match env::var("XDG_DATA_DIRS") {
+        Ok(dirs) if !dirs.is_empty() => dirs,
+        _ => "/usr/local/share:/usr/share".to_string(),
+    };
+    for dir in data_dirs.split(':').filter(|dir| !dir.is_empty()) {
+        paths.push(PathBuf::from(dir));
+    }
+
+    paths
+        .into_iter()
+        .map(|path| path.join("flatkvm").join("templates"))
+        .collect()
+}
+
+// Lists the templates found in the search paths. Each one lives in its own
+// directory, holding the image, the kernel, the metadata file and,
+// optionally, a manifest with their digests.
+pub fn list_templates() -> Vec<TemplateInfo> {
+    let mut templates: Vec<TemplateInfo> = Vec::new();
+
+    for path in search_paths() {
+        let entries = match fs::read_dir(&path) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+
+        // Sorted, so the result doesn't depend on the directory order.
+        let mut entries: Vec<_> = entries.filter_map(Result::ok).collect();
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if templates.iter().any(|t| t.name == name) {
+                continue;
+            }
+            if let Some(info) = TemplateInfo::load(&name, &entry.path()) {
+                templates.push(info);
+            }
+        }
+    }
+
+    templates
+}
+
+// Returns the newest compatible template. Among templates with the same
+// version, the one found first in the search paths wins, as max_by keeps
+// the last of equal elements.
+pub fn find_template(agent_version: &str) -> Option<TemplateInfo> {
+    list_templates()
+        .into_iter()
+        .rev()
+        .filter(|t| t.is_compatible(agent_version))
+        .max_by(|a, b| compare_versions(&a.metadata.version, &b.metadata.version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_compare_numerically() {
+        assert_eq!(compare_versions("1.10", "1.9"), Ordering::Greater);
+        assert_eq!(compare_versions("0.9.1", "0.10"), Ordering::Less);
+        assert_eq!(compare_versions("2", "10"), Ordering::Less);
+    }
+
+    #[test]
+    fn missing_components_are_zero() {
+        assert_eq!(compare_versions("1.2", "1.2.0"), Ordering::Equal);
+        assert_eq!(compare_versions("1.2", "1.2.1"), Ordering::Less);
+        assert_eq!(compare_versions("1.2.0.1", "1.2"), Ordering::Greater);
+    }
+
+    #[test]
+    fn invalid_components_are_zero() {
+        assert_eq!(compare_versions("1.x", "1.0"), Ordering::Equal);
+        assert_eq!(compare_versions("", "0"), Ordering::Equal);
+    }
+}
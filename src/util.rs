@@ -14,7 +14,16 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::ptr;
 use std::thread::sleep;
 use std::time::Duration;
 
@@ -35,3 +44,173 @@ fn _open_socket(path: String, iter: i32) -> Result<UnixStream, std::io::Error> {
 pub fn open_socket(path: String) -> Result<UnixStream, std::io::Error> {
     _open_socket(path, 0)
 }
+
+// Secrets must be owned by the current user and only accessible by them.
+pub fn check_secret_file(path: &str) -> Result<(), String> {
+    let metadata = fs::metadata(path).map_err(|err| format!("{}: {}", path, err))?;
+    if metadata.uid() != unsafe { libc::geteuid() } {
+        return Err(format!(
+            "{}: secret file must be owned by the current user",
+            path
+        ));
+    }
+    if metadata.permissions().mode() & 0o077 != 0 {
+        return Err(format!("{}: secret file permissions must be 0600", path));
+    }
+    Ok(())
+}
+
+// Writes "data" to the stream, passing "fd" along as ancillary data.
+pub fn send_with_fd(stream: &UnixStream, data: &[u8], fd: RawFd) -> Result<(), io::Error> {
+    let fd_len = mem::size_of::<RawFd>() as u32;
+    let space = unsafe { libc::CMSG_SPACE(fd_len) } as usize;
+    // Use u64 as backing storage to keep the control header aligned.
+    let mut cmsg_buf = vec![0u64; space.div_ceil(8)];
+
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fd_len) as _;
+        ptr::copy_nonoverlapping(
+            &fd as *const RawFd as *const u8,
+            libc::CMSG_DATA(cmsg),
+            fd_len as usize,
+        );
+
+        let len = libc::sendmsg(stream.as_raw_fd(), &msg, 0);
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if (len as usize) < data.len() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "short write"));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn cache_dir() -> Option<PathBuf> {
+    let cache_dir = match env::var("XDG_CACHE_HOME") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => PathBuf::from(env::var("HOME").ok()?).join(".cache"),
+    };
+    Some(cache_dir.join("flatkvm"))
+}
+
+pub fn runtime_dir() -> PathBuf {
+    match env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => env::temp_dir(),
+    }
+}
+
+// Restricts the thread "tid" to run on the host CPUs in "cpus".
+pub fn set_thread_affinity(tid: i32, cpus: &[usize]) -> Result<(), String> {
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    for cpu in cpus {
+        if *cpu >= libc::CPU_SETSIZE as usize {
+            return Err(format!("invalid host CPU {}", cpu));
+        }
+        unsafe { libc::CPU_SET(*cpu, &mut set) };
+    }
+
+    let ret = unsafe { libc::sched_setaffinity(tid, mem::size_of::<libc::cpu_set_t>(), &set) };
+    if ret < 0 {
+        Err(format!(
+            "can't set the affinity of thread {}: {}",
+            tid,
+            io::Error::last_os_error()
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn flatten_opts(prefix: &str, value: &Value, opts: &mut Vec<String>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                if prefix.is_empty() {
+                    flatten_opts(key, value, opts);
+                } else {
+                    flatten_opts(&format!("{}.{}", prefix, key), value, opts);
+                }
+            }
+        }
+        Value::Bool(value) => {
+            opts.push(format!("{}={}", prefix, if *value { "on" } else { "off" }))
+        }
+        Value::Number(value) => opts.push(format!("{}={}", prefix, value)),
+        Value::String(value) => opts.push(format!("{}={}", prefix, value.replace(',', ",,"))),
+        _ => (),
+    }
+}
+
+// Renders a QMP-style object as a QEMU command line option value, with
+// nested objects as dotted keys. The value of "implied_key", if any, goes
+// first without its key, as expected by options such as -device.
+pub fn qemu_opts(value: &Value, implied_key: &str) -> String {
+    let mut opts = Vec::new();
+    let mut value = value.clone();
+
+    if let Some(map) = value.as_object_mut() {
+        if let Some(Value::String(implied)) = map.remove(implied_key) {
+            opts.push(implied.replace(',', ",,"));
+        }
+    }
+    flatten_opts("", &value, &mut opts);
+
+    opts.join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn opts_escape_commas() {
+        let value = json!({
+            "driver": "virtio-blk,x",
+            "serial": "a,b",
+            "file": {"filename": "/tmp/x,,y"},
+        });
+        assert_eq!(
+            qemu_opts(&value, "driver"),
+            "virtio-blk,,x,file.filename=/tmp/x,,,,y,serial=a,,b"
+        );
+    }
+
+    #[test]
+    fn opts_put_the_implied_key_first() {
+        let value = json!({"id": "vda", "driver": "virtio-blk-pci", "bootindex": 1});
+        assert_eq!(
+            qemu_opts(&value, "driver"),
+            "virtio-blk-pci,bootindex=1,id=vda"
+        );
+    }
+
+    #[test]
+    fn opts_flatten_nested_objects() {
+        let value = json!({
+            "node-name": "data",
+            "read-only": false,
+            "file": {"driver": "file", "aio": {"mode": "native"}},
+        });
+        assert_eq!(
+            qemu_opts(&value, ""),
+            "file.aio.mode=native,file.driver=file,node-name=data,read-only=off"
+        );
+    }
+}
@@ -0,0 +1,849 @@
+// flatkvm-qemu
+// Copyright (C) 2019  Sergio Lopez <slp@sinrega.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use crate::agent::AgentHost;
+use crate::cgroup::Cgroup;
+use crate::drive::{QemuDrive, QemuDriveBus, QemuDriveMount, QemuDriveRole, QemuDriveSecret};
+use crate::qmpconn::{QmpConn, QmpEvent, QMP_CLOSED};
+use crate::runner::{QemuRunner, QemuSharedDir, QemuSharedDirTransport, QemuSharedDirType};
+use crate::util::set_thread_affinity;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs::{self, File};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub(crate) const STATE_FILE: &str = "state";
+pub(crate) const STATE_INFO_FILE: &str = "state.json";
+pub(crate) const STATE_TEMPLATE_OVERLAY_FILE: &str = "template.qcow2";
+
+// Binds a saved VM state to the disks, kernel and machine configuration
+// it was taken with.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SavedStateInfo {
+    pub data_disk: String,
+    pub data_disk_sha256: String,
+    #[serde(default)]
+    pub template_sha256: String,
+    #[serde(default)]
+    pub kernel_sha256: String,
+    pub vcpu_num: u32,
+    pub ram_mb: u32,
+    #[serde(default)]
+    pub maxmem_mb: Option<u32>,
+}
+
+fn vcpu_thread_ids(qmp: &QmpConn) -> Result<Vec<i32>, String> {
+    Ok(qmp
+        .query_cpus_fast()?
+        .iter()
+        .map(|cpu| cpu.thread_id as i32)
+        .collect())
+}
+
+pub(crate) fn pin_vcpu_threads(qmp: &QmpConn, cpus: &[usize]) -> Result<(), String> {
+    if cpus.is_empty() {
+        return Err("no host CPUs to pin the vCPUs to".to_string());
+    }
+
+    let tids = vcpu_thread_ids(qmp)?;
+    for (i, tid) in tids.iter().enumerate() {
+        if cpus.len() == tids.len() {
+            set_thread_affinity(*tid, &cpus[i..=i])?;
+        } else {
+            set_thread_affinity(*tid, cpus)?;
+        }
+    }
+    Ok(())
+}
+
+// Returns the pid of the process owning the thread "tid". This finds QEMU
+// from its vCPU threads even when it's not our child, as when it runs
+// under bubblewrap.
+fn thread_group_id(tid: i32) -> Result<i32, String> {
+    let path = format!("/proc/{}/status", tid);
+    let status = fs::read_to_string(&path).map_err(|err| format!("{}: {}", path, err))?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Tgid:"))
+        .and_then(|tgid| tgid.trim().parse().ok())
+        .ok_or_else(|| format!("{}: no Tgid", path))
+}
+
+fn qemu_pid(qmp: &QmpConn) -> Result<i32, String> {
+    match vcpu_thread_ids(qmp)?.first() {
+        Some(tid) => thread_group_id(*tid),
+        None => Err("no vCPU threads found".to_string()),
+    }
+}
+
+pub(crate) fn pin_emulator_threads(qmp: &QmpConn, cpus: &[usize]) -> Result<(), String> {
+    if cpus.is_empty() {
+        return Err("no host CPUs to pin the emulator threads to".to_string());
+    }
+
+    let vcpu_tids = vcpu_thread_ids(qmp)?;
+    let pid = match vcpu_tids.first() {
+        Some(tid) => thread_group_id(*tid)?,
+        None => return Err("no vCPU threads found".to_string()),
+    };
+    let task_dir = format!("/proc/{}/task", pid);
+    let entries = fs::read_dir(&task_dir).map_err(|err| format!("{}: {}", task_dir, err))?;
+    for entry in entries.filter_map(Result::ok) {
+        let tid: i32 = match entry.file_name().to_string_lossy().parse() {
+            Ok(tid) => tid,
+            Err(_) => continue,
+        };
+        if !vcpu_tids.contains(&tid) {
+            set_thread_affinity(tid, cpus)?;
+        }
+    }
+    Ok(())
+}
+
+// Waits on "events", which must have been subscribed before the device_del
+// request, for device "id" to be removed by the guest.
+fn wait_device_deleted(
+    events: &Receiver<QmpEvent>,
+    id: &str,
+    timeout: Duration,
+) -> Result<(), String> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let remaining = deadline
+            .checked_duration_since(Instant::now())
+            .unwrap_or_default();
+        match events.recv_timeout(remaining) {
+            Ok(QmpEvent::DeviceDeleted {
+                device: Some(device),
+                ..
+            }) if device == id => return Ok(()),
+            Ok(_) => (),
+            Err(_) => return Err(format!("timed out removing device {}", id)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GuestPanic {
+    pub action: String,
+    pub info: Option<Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CrashReport {
+    pub name: String,
+    pub timestamp: u64,
+    pub panic: Option<GuestPanic>,
+    pub qemu_version: Option<String>,
+    pub status: Option<String>,
+    pub memory_dump: Option<String>,
+    pub qemu_log: Option<String>,
+    pub serial_log: Option<String>,
+}
+
+const CRASH_REPORT_FILE: &str = "report.json";
+const CRASH_MEMORY_FILE: &str = "memory.elf";
+const CRASH_QEMU_LOG_FILE: &str = "qemu.log";
+const CRASH_SERIAL_LOG_FILE: &str = "serial.log";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShutdownStage {
+    // The VM had already exited before shutdown was requested.
+    Exited,
+    Powerdown,
+    Quit,
+    Terminate,
+    Kill,
+}
+
+// A running QEMU instance, along with its QMP connection and the helper
+// processes serving its devices, such as virtiofsd.
+pub struct QemuVm {
+    child: Child,
+    qmp: QmpConn,
+    helpers: Vec<(String, Child)>,
+    drives: Vec<HotpluggedDrive>,
+    cgroup: Option<Cgroup>,
+    panic: Arc<Mutex<Option<GuestPanic>>>,
+    next_hotplug_id: u32,
+}
+
+// Backend objects of a hot-plugged drive, to be deleted once its device
+// is gone.
+struct HotpluggedDrive {
+    id: String,
+    secret: Option<String>,
+    nodes: Vec<String>,
+    iothread: Option<String>,
+}
+
+impl QemuVm {
+    pub fn new(child: Child, qmp: QmpConn) -> QemuVm {
+        let panic = Arc::new(Mutex::new(None));
+        let panic_clone = panic.clone();
+        qmp.on_event(move |event| {
+            if let QmpEvent::GuestPanicked { action, info } = event {
+                *panic_clone.lock().unwrap() = Some(GuestPanic { action, info });
+            }
+        });
+
+        QemuVm {
+            child,
+            qmp,
+            helpers: Vec::new(),
+            drives: Vec::new(),
+            cgroup: None,
+            panic,
+            next_hotplug_id: 0,
+        }
+    }
+
+    // Returns the last panic reported by the guest through pvpanic.
+    pub fn get_panic(&self) -> Option<GuestPanic> {
+        self.panic.lock().unwrap().clone()
+    }
+
+    // Writes a crash report to a new directory in the runner's crash dir,
+    // with a summary, the QEMU and serial logs and, if "dump_memory" is
+    // set, an ELF core of the guest memory. Returns the directory, which
+    // can be attached to bug reports.
+    pub fn write_crash_report(
+        &self,
+        runner: &QemuRunner,
+        dump_memory: bool,
+    ) -> Result<PathBuf, String> {
+        let crash_dir = match runner.get_crash_dir() {
+            Some(dir) => dir,
+            None => return Err("can't find the crash report directory".to_string()),
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let dir = crash_dir.join(format!("{}-{}", runner.get_name(), timestamp));
+        fs::create_dir_all(&dir).map_err(|err| format!("{}: {}", dir.display(), err))?;
+
+        let mut report = CrashReport {
+            name: runner.get_name().to_string(),
+            timestamp,
+            panic: self.get_panic(),
+            qemu_version: self.qmp.query_version().ok().map(|v| {
+                format!(
+                    "{}.{}.{}{}",
+                    v.qemu.major, v.qemu.minor, v.qemu.micro, v.package
+                )
+            }),
+            status: self.qmp.query_status().ok().map(|s| s.status),
+            memory_dump: None,
+            qemu_log: None,
+            serial_log: None,
+        };
+
+        if dump_memory {
+            let path = dir.join(CRASH_MEMORY_FILE);
+            let dump = File::create(&path).map_err(|err| format!("{}: {}", path.display(), err))?;
+            self.qmp.getfd("flatkvm-dump", dump.as_raw_fd())?;
+            if let Err(err) = self.qmp.dump_guest_memory("fd:flatkvm-dump") {
+                let _ = self.qmp.closefd("flatkvm-dump");
+                return Err(err);
+            }
+            report.memory_dump = Some(CRASH_MEMORY_FILE.to_string());
+        }
+        let logs = [
+            (
+                runner.get_qemu_log(),
+                CRASH_QEMU_LOG_FILE,
+                &mut report.qemu_log,
+            ),
+            (
+                runner.get_serial_log(),
+                CRASH_SERIAL_LOG_FILE,
+                &mut report.serial_log,
+            ),
+        ];
+        for (log, file, entry) in logs {
+            // Logs that can't be copied are left out of the report.
+            if let Some(log) = log {
+                if fs::copy(log, dir.join(file)).is_ok() {
+                    *entry = Some(file.to_string());
+                }
+            }
+        }
+
+        let data = serde_json::to_string_pretty(&report).map_err(|err| err.to_string())?;
+        fs::write(dir.join(CRASH_REPORT_FILE), data).map_err(|err| err.to_string())?;
+        Ok(dir)
+    }
+
+    // Has the helpers spawned for this VM join "cgroup", which is removed
+    // once the VM is gone.
+    pub(crate) fn set_cgroup(&mut self, cgroup: Option<Cgroup>) {
+        self.cgroup = cgroup;
+    }
+
+    pub fn get_qmp_conn(&self) -> &QmpConn {
+        &self.qmp
+    }
+
+    // Returns the pid of the QEMU process, which isn't our child when it
+    // runs under bubblewrap.
+    pub fn get_pid(&self) -> Result<u32, String> {
+        qemu_pid(&self.qmp).map(|pid| pid as u32)
+    }
+
+    // Pins the vCPU threads to the host CPUs in "cpus": one vCPU to each
+    // CPU if there are as many of them as vCPUs, or all of them to the
+    // whole set otherwise.
+    pub fn pin_vcpus(&self, cpus: &[usize]) -> Result<(), String> {
+        pin_vcpu_threads(&self.qmp, cpus)
+    }
+
+    // Pins every QEMU thread other than the vCPUs (main loop, iothreads,
+    // workers) to the host CPUs in "cpus". Threads created afterwards
+    // inherit the affinity of the main loop.
+    pub fn pin_emulator_threads(&self, cpus: &[usize]) -> Result<(), String> {
+        pin_emulator_threads(&self.qmp, cpus)
+    }
+
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>, String> {
+        self.child.try_wait().map_err(|err| err.to_string())
+    }
+
+    pub fn wait(&mut self) -> Result<ExitStatus, String> {
+        let status = self.child.wait().map_err(|err| err.to_string())?;
+        self.stop_helpers();
+        self.remove_cgroup();
+        Ok(status)
+    }
+
+    // Freezes the VM. If "agent" is given, the agent is notified first,
+    // so it can get the guest ready to be stopped.
+    pub fn pause(&self, agent: Option<&mut AgentHost>) -> Result<(), String> {
+        if let Some(agent) = agent {
+            agent.notify_pause()?;
+        }
+        self.qmp.stop()
+    }
+
+    // Thaws the VM. If "agent" is given, the guest clock is resynced with
+    // the host's, as it has fallen behind while the VM was paused.
+    pub fn resume(&self, agent: Option<&mut AgentHost>) -> Result<(), String> {
+        self.qmp.cont()?;
+        if let Some(agent) = agent {
+            agent.request_time_sync()?;
+        }
+        Ok(())
+    }
+
+    // Waits up to "timeout" for an outgoing migration to finish, and
+    // cancels it if it doesn't.
+    fn wait_migration(&self, timeout: Duration) -> Result<(), String> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            if Instant::now() >= deadline {
+                let _ = self.qmp.execute("migrate_cancel", None);
+                return Err("timed out saving the VM state".to_string());
+            }
+            let info = self.qmp.query_migrate()?;
+            match info.status.as_deref() {
+                Some("completed") => return Ok(()),
+                Some("failed") | Some("cancelled") => {
+                    return Err(format!(
+                        "migration failed: {}",
+                        info.error_desc.unwrap_or_default()
+                    ))
+                }
+                _ => sleep(POLL_INTERVAL),
+            }
+        }
+    }
+
+    // Waits up to "timeout" for an incoming migration to finish and
+    // resumes the VM.
+    pub(crate) fn wait_incoming(&self, timeout: Duration) -> Result<(), String> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            if Instant::now() >= deadline {
+                return Err("timed out loading the VM state".to_string());
+            }
+            let status = self.qmp.query_status()?;
+            match status.status.as_str() {
+                "inmigrate" => sleep(POLL_INTERVAL),
+                "paused" | "postmigrate" => return self.qmp.cont(),
+                "running" => return Ok(()),
+                other => return Err(format!("failed to load VM state: {}", other)),
+            }
+        }
+    }
+
+    // Hibernates the VM: pauses it, saves its state to "dir" and quits
+    // QEMU. "runner" must be the one that started this VM, and is used
+    // later to restore it with QemuRunner::restore. If saving takes longer
+    // than "timeout", it's cancelled and the VM resumed.
+    pub fn save_state(
+        &mut self,
+        runner: &QemuRunner,
+        dir: &str,
+        timeout: Duration,
+    ) -> Result<(), String> {
+        runner.check_saveable()?;
+
+        let dir = Path::new(dir);
+        fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+        self.save_base_state(&runner.overlay_path("template"), dir, timeout)?;
+
+        let info = runner.saved_state_info()?;
+        let data = serde_json::to_string(&info).map_err(|err| err.to_string())?;
+        fs::write(dir.join(STATE_INFO_FILE), data).map_err(|err| err.to_string())
+    }
+
+    // Saves the VM state and a copy of the template overlay to "dir", and
+    // quits QEMU.
+    pub(crate) fn save_base_state(
+        &mut self,
+        template_overlay: &str,
+        dir: &Path,
+        timeout: Duration,
+    ) -> Result<(), String> {
+        let state = File::create(dir.join(STATE_FILE)).map_err(|err| err.to_string())?;
+
+        self.qmp.stop()?;
+        let result = self
+            .qmp
+            .getfd("flatkvm-state", state.as_raw_fd())
+            .and_then(|_| self.qmp.migrate("fd:flatkvm-state"))
+            .and_then(|_| self.wait_migration(timeout));
+        if let Err(err) = result {
+            let _ = self.qmp.cont();
+            return Err(err);
+        }
+
+        // QEMU may close the connection before replying.
+        let _ = self.qmp.execute("quit", None);
+        self.wait()?;
+        self.stop_helpers();
+
+        fs::copy(template_overlay, dir.join(STATE_TEMPLATE_OVERLAY_FILE))
+            .map_err(|err| err.to_string())?;
+        Ok(())
+    }
+
+    // Adds "drive" to the running VM, with "node_name" as the name of both
+    // its top block node and its device.
+    pub(crate) fn hotplug_drive(
+        &mut self,
+        runner: &QemuRunner,
+        drive: &QemuDrive,
+        node_name: &str,
+        serial: &str,
+        bus: QemuDriveBus,
+        overlay: Option<&str>,
+    ) -> Result<(), String> {
+        drive.validate()?;
+        if let Some(QemuDriveSecret::Fd(_)) = drive.get_secret() {
+            return Err("drives with fd secrets can't be hot-plugged".to_string());
+        }
+
+        let mut hotplugged = HotpluggedDrive {
+            id: node_name.to_string(),
+            secret: None,
+            nodes: Vec::new(),
+            iothread: None,
+        };
+        let result = self.add_drive_backend(
+            runner,
+            drive,
+            node_name,
+            serial,
+            bus,
+            overlay,
+            &mut hotplugged,
+        );
+        if result.is_err() {
+            self.delete_drive_backend(&hotplugged);
+        } else {
+            self.drives.push(hotplugged);
+        }
+        result
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn add_drive_backend(
+        &self,
+        runner: &QemuRunner,
+        drive: &QemuDrive,
+        node_name: &str,
+        serial: &str,
+        bus: QemuDriveBus,
+        overlay: Option<&str>,
+        hotplugged: &mut HotpluggedDrive,
+    ) -> Result<(), String> {
+        if let Some(secret) = drive.secret_object(node_name) {
+            self.qmp.execute("object-add", Some(secret))?;
+            hotplugged.secret = Some(format!("{}-secret", node_name));
+        }
+        for node in drive.blockdev_nodes(node_name, overlay) {
+            let name = node["node-name"].as_str().unwrap_or_default().to_string();
+            self.qmp.execute("blockdev-add", Some(node))?;
+            hotplugged.nodes.push(name);
+        }
+
+        let props = match bus {
+            QemuDriveBus::Virtio => {
+                let iothread = runner.blk_iothread(node_name);
+                if let Some(iothread) = &iothread {
+                    self.qmp.execute(
+                        "object-add",
+                        Some(json!({ "qom-type": "iothread", "id": iothread })),
+                    )?;
+                    hotplugged.iothread = Some(iothread.to_string());
+                }
+                drive.device_props(
+                    node_name,
+                    Some(serial),
+                    iothread.as_deref(),
+                    runner.blk_num_queues(),
+                )
+            }
+            QemuDriveBus::Usb => drive.usb_device_props(node_name, serial),
+        };
+        self.qmp.device_add(props)
+    }
+
+    // Deletes the backend objects of a drive, in the reverse order they
+    // were created.
+    fn delete_drive_backend(&self, hotplugged: &HotpluggedDrive) {
+        if let Some(iothread) = &hotplugged.iothread {
+            let _ = self
+                .qmp
+                .execute("object-del", Some(json!({ "id": iothread })));
+        }
+        for node in hotplugged.nodes.iter().rev() {
+            let _ = self
+                .qmp
+                .execute("blockdev-del", Some(json!({ "node-name": node })));
+        }
+        if let Some(secret) = &hotplugged.secret {
+            let _ = self
+                .qmp
+                .execute("object-del", Some(json!({ "id": secret })));
+        }
+    }
+
+    // Attaches a disk image or ISO to the running VM and asks the agent
+    // to mount it at "mount_point". USB drives need the VM to have been
+    // started with a USB controller.
+    pub fn add_drive(
+        &mut self,
+        runner: &QemuRunner,
+        agent: &mut AgentHost,
+        drive: QemuDrive,
+        bus: QemuDriveBus,
+        role: QemuDriveRole,
+        mount_point: String,
+    ) -> Result<QemuDriveMount, String> {
+        let id = format!("hotdrive{}", self.next_hotplug_id);
+        self.next_hotplug_id += 1;
+
+        self.hotplug_drive(runner, &drive, &id, &id, bus, None)?;
+        let mount = QemuDriveMount {
+            role,
+            serial: id,
+            mount_point,
+            readonly: drive.is_readonly(),
+            mkfs: false,
+            bus,
+        };
+
+        match agent.request_drive_mount(mount.clone()) {
+            Ok(0) => Ok(mount),
+            Ok(status) => {
+                let _ = self.unplug_drive(&mount.serial, Duration::from_secs(5));
+                Err(format!(
+                    "agent failed to mount {}: {}",
+                    mount.serial, status
+                ))
+            }
+            Err(err) => {
+                let _ = self.unplug_drive(&mount.serial, Duration::from_secs(5));
+                Err(err)
+            }
+        }
+    }
+
+    // Detaches a drive added with add_drive, once the agent has unmounted
+    // it and the guest has released the device.
+    pub fn remove_drive(
+        &mut self,
+        agent: &mut AgentHost,
+        serial: &str,
+        timeout: Duration,
+    ) -> Result<(), String> {
+        match agent.request_drive_unmount(serial.to_string())? {
+            0 => self.unplug_drive(serial, timeout),
+            status => Err(format!("agent failed to unmount {}: {}", serial, status)),
+        }
+    }
+
+    fn unplug_drive(&mut self, id: &str, timeout: Duration) -> Result<(), String> {
+        let pos = match self.drives.iter().position(|drive| drive.id == id) {
+            Some(pos) => pos,
+            None => return Err(format!("unknown drive {}", id)),
+        };
+
+        let events = self.qmp.subscribe();
+        self.qmp.device_del(id)?;
+        wait_device_deleted(&events, id, timeout)?;
+        let hotplugged = self.drives.remove(pos);
+        self.delete_drive_backend(&hotplugged);
+        Ok(())
+    }
+
+    // Exports "source" to the running VM through virtiofs, spawning a
+    // virtiofsd instance for it, with "tag" as the name of its chardev and
+    // device, and as the mount tag.
+    pub(crate) fn hotplug_shared_dir(
+        &mut self,
+        runner: &QemuRunner,
+        tag: &str,
+        source: &str,
+        readonly: bool,
+    ) -> Result<(), String> {
+        let sock_path = runner.virtiofsd_sock_path(tag);
+        let _ = fs::remove_file(&sock_path);
+
+        let mut command = Command::new(runner.get_virtiofsd());
+        command
+            .arg(format!("--socket-path={}", sock_path))
+            .arg(format!("--shared-dir={}", source))
+            .arg("--cache=auto")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        if readonly {
+            command.arg("--readonly");
+        }
+        if let Some(cgroup) = &self.cgroup {
+            cgroup.enter_on_spawn(&mut command)?;
+        }
+        let mut virtiofsd = command.spawn().map_err(|err| err.to_string())?;
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !Path::new(&sock_path).exists() && Instant::now() < deadline {
+            sleep(POLL_INTERVAL);
+        }
+
+        let result = self
+            .qmp
+            .execute(
+                "chardev-add",
+                Some(json!({
+                    "id": tag,
+                    "backend": {
+                        "type": "socket",
+                        "data": {
+                            "addr": { "type": "unix", "data": { "path": sock_path } },
+                            "server": false,
+                        },
+                    },
+                })),
+            )
+            .and_then(|_| {
+                self.qmp.device_add(json!({
+                    "driver": "vhost-user-fs-pci",
+                    "id": tag,
+                    "chardev": tag,
+                    "tag": tag,
+                }))
+            });
+        if let Err(err) = result {
+            let _ = virtiofsd.kill();
+            let _ = virtiofsd.wait();
+            return Err(err);
+        }
+
+        self.helpers.push((tag.to_string(), virtiofsd));
+        Ok(())
+    }
+
+    // Grants the VM access to "source", exporting it through virtiofs and
+    // asking the agent to mount it. The VM must have been started with
+    // shared dir hot-plugging enabled.
+    pub fn add_shared_dir(
+        &mut self,
+        runner: &QemuRunner,
+        agent: &mut AgentHost,
+        dir_type: QemuSharedDirType,
+        source: String,
+        readonly: bool,
+    ) -> Result<QemuSharedDir, String> {
+        let tag = format!("hotdir{}", self.next_hotplug_id);
+        self.next_hotplug_id += 1;
+
+        self.hotplug_shared_dir(runner, &tag, &source, readonly)?;
+        let shared_dir = QemuSharedDir {
+            dir_type,
+            app_name: runner.get_name().to_string(),
+            source,
+            tag,
+            readonly,
+            transport: QemuSharedDirTransport::Virtiofs,
+        };
+
+        match agent.request_mount(shared_dir.clone()) {
+            Ok(0) => Ok(shared_dir),
+            Ok(status) => {
+                let _ = self.unplug_shared_dir(&shared_dir.tag, Duration::from_secs(5));
+                Err(format!(
+                    "agent failed to mount {}: {}",
+                    shared_dir.tag, status
+                ))
+            }
+            Err(err) => {
+                let _ = self.unplug_shared_dir(&shared_dir.tag, Duration::from_secs(5));
+                Err(err)
+            }
+        }
+    }
+
+    // Revokes the access to a shared dir added with add_shared_dir, once
+    // the agent has unmounted it.
+    pub fn remove_shared_dir(
+        &mut self,
+        agent: &mut AgentHost,
+        tag: &str,
+        timeout: Duration,
+    ) -> Result<(), String> {
+        match agent.request_unmount(tag.to_string())? {
+            0 => self.unplug_shared_dir(tag, timeout),
+            status => Err(format!("agent failed to unmount {}: {}", tag, status)),
+        }
+    }
+
+    fn unplug_shared_dir(&mut self, tag: &str, timeout: Duration) -> Result<(), String> {
+        let events = self.qmp.subscribe();
+        self.qmp.device_del(tag)?;
+        wait_device_deleted(&events, tag, timeout)?;
+        self.qmp
+            .execute("chardev-remove", Some(json!({ "id": tag })))?;
+
+        if let Some(pos) = self.helpers.iter().position(|(id, _)| id == tag) {
+            let (_, mut helper) = self.helpers.remove(pos);
+            let _ = helper.kill();
+            let _ = helper.wait();
+        }
+        Ok(())
+    }
+
+    // Only succeeds once QEMU and its helpers are gone. Leftovers are
+    // removed by QemuRunner::cleanup.
+    fn remove_cgroup(&mut self) {
+        if let Some(cgroup) = &self.cgroup {
+            let _ = cgroup.remove();
+        }
+    }
+
+    fn stop_helpers(&mut self) {
+        for (_, mut helper) in self.helpers.drain(..) {
+            let _ = helper.kill();
+            let _ = helper.wait();
+        }
+    }
+
+    // Waits up to "timeout" for the QEMU process to exit. If "events" is
+    // given, receiving a SHUTDOWN event on it restarts the timeout, giving
+    // QEMU some time to tear down after the guest has powered off.
+    fn wait_exit(
+        &mut self,
+        timeout: Duration,
+        events: Option<&Receiver<QmpEvent>>,
+    ) -> Result<bool, String> {
+        let mut deadline = Instant::now() + timeout;
+
+        while Instant::now() < deadline {
+            if self.try_wait()?.is_some() {
+                return Ok(true);
+            }
+            if let Some(events) = events {
+                while let Ok(event) = events.try_recv() {
+                    if let QmpEvent::Shutdown { .. } = event {
+                        deadline = Instant::now() + timeout;
+                    }
+                }
+            }
+            sleep(POLL_INTERVAL);
+        }
+
+        Ok(self.try_wait()?.is_some())
+    }
+
+    // Stops the VM, escalating through each stage until the QEMU process
+    // exits: an ACPI powerdown request, a QMP quit, SIGTERM and, finally,
+    // SIGKILL. Returns the stage that brought the VM down.
+    pub fn shutdown(&mut self, timeout: Duration) -> Result<ShutdownStage, String> {
+        let stage = self.shutdown_qemu(timeout)?;
+        self.stop_helpers();
+        self.remove_cgroup();
+        Ok(stage)
+    }
+
+    fn shutdown_qemu(&mut self, timeout: Duration) -> Result<ShutdownStage, String> {
+        if self.try_wait()?.is_some() {
+            return Ok(ShutdownStage::Exited);
+        }
+
+        // A QEMU that doesn't reply within "timeout" is treated like one
+        // that ignored the request, moving on to the next stage.
+        let events = self.qmp.subscribe();
+        if self
+            .qmp
+            .execute_timeout("system_powerdown", None, timeout)
+            .is_ok()
+            && self.wait_exit(timeout, Some(&events))?
+        {
+            return Ok(ShutdownStage::Powerdown);
+        }
+
+        // QEMU may close the socket before its reply to quit gets through.
+        let quit = match self.qmp.execute_timeout("quit", None, timeout) {
+            Ok(_) => true,
+            Err(err) => err == QMP_CLOSED,
+        };
+        if quit && self.wait_exit(timeout, None)? {
+            return Ok(ShutdownStage::Quit);
+        }
+
+        if unsafe { libc::kill(self.child.id() as libc::pid_t, libc::SIGTERM) } == 0
+            && self.wait_exit(timeout, None)?
+        {
+            return Ok(ShutdownStage::Terminate);
+        }
+
+        self.child.kill().map_err(|err| err.to_string())?;
+        self.wait()?;
+        Ok(ShutdownStage::Kill)
+    }
+}